@@ -0,0 +1,80 @@
+//! Implements the optional TOML configuration file
+//!
+//! Every setting corresponds to the command line option with the same name,
+//! which takes precedence if it is given as well.  Only the options of
+//! [`Config`] are supported, all others can only be given on the command line.
+
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {path:?}: {source}")]
+    IO {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to parse {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+/// The contents of a configuration file
+///
+/// The values are validated once merged with the command line, see
+/// `merge_config()`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub verbose: Option<u8>,
+    pub quiet: Option<bool>,
+    pub log_format: Option<String>,
+    pub log_connections: Option<bool>,
+    pub dns: Option<Vec<IpAddr>>,
+    pub dns4: Option<Ipv4Addr>,
+    pub dns6: Option<Ipv6Addr>,
+    pub tun_ipv4: Option<String>,
+    pub tun_ipv6: Option<String>,
+    pub resolv_search: Option<Vec<String>>,
+    pub keep_resolv_conf: Option<bool>,
+    pub map_root: Option<bool>,
+    pub device_name: Option<String>,
+    pub mtu: Option<u32>,
+    pub exit_country: Option<String>,
+}
+
+/// Returns the default location of the configuration file.
+///
+/// This is `$XDG_CONFIG_HOME/oniux/config.toml`, with `XDG_CONFIG_HOME`
+/// falling back to `~/.config` as per the XDG Base Directory Specification.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(base.join("oniux").join("config.toml"))
+}
+
+/// Loads the configuration file at `path`.
+///
+/// Unknown settings are rejected with an error pointing to their location.
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|source| ConfigError::IO {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    toml::from_str(&content).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
@@ -0,0 +1,309 @@
+//! Implements a read-only control socket for the running instance
+//!
+//! The protocol is line-oriented: every line sent by a client is a command,
+//! which is answered with a single line starting with either `OK` or `ERR`.
+
+use std::{
+    env, fs, io,
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use log::{debug, warn};
+use nix::{
+    sys::stat::{umask, Mode},
+    unistd::Pid,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    runtime,
+};
+
+/// The commands answered on the control socket
+const COMMANDS: [&str; 5] = ["help", "pid", "status", "uptime", "version"];
+
+/// The commands which would modify the running instance
+///
+/// These are rejected explicitly, so that clients can tell them apart from
+/// typos.
+const MUTATING_COMMANDS: [&str; 5] = ["newnym", "reload", "set", "shutdown", "signal"];
+
+/// What `help` lists as out of scope, besides the [`MUTATING_COMMANDS`]
+///
+/// onion-tunnel does not expose these to oniux.
+const OUT_OF_SCOPE: &str = "bootstrap progress, traffic counters, exit relay";
+
+/// The state of the onion-tunnel, as reported by `status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TunnelState {
+    /// The onion-tunnel is being created.
+    Starting,
+    /// The onion-tunnel has been created and runs.
+    Running,
+    /// The onion-tunnel failed to be created or stopped running.
+    Stopped,
+}
+
+impl TunnelState {
+    /// The answer to `status` in this state
+    fn name(self) -> &'static str {
+        match self {
+            Self::Starting => "starting",
+            Self::Running => "running",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("the relative control socket path {path:?} requires $XDG_RUNTIME_DIR")]
+    NoRuntimeDir { path: PathBuf },
+    #[error("control socket {path:?} already exists, is another instance using it?")]
+    Exists { path: PathBuf },
+    #[error("failed to create control socket {path:?}: {source}")]
+    Bind { path: PathBuf, source: io::Error },
+}
+
+/// The state of the running instance, as reported on the control socket
+pub struct Status {
+    pid: Pid,
+    started: Instant,
+    tunnel: AtomicU8,
+}
+
+impl Status {
+    /// Creates the state for the isolation process `pid`.
+    pub fn new(pid: Pid) -> Self {
+        Self {
+            pid,
+            started: Instant::now(),
+            tunnel: AtomicU8::new(TunnelState::Starting as u8),
+        }
+    }
+
+    /// Records the state of the onion-tunnel.
+    ///
+    /// Being running does not imply that Tor has finished bootstrapping.
+    pub fn set_tunnel(&self, state: TunnelState) {
+        self.tunnel.store(state as u8, Ordering::SeqCst);
+    }
+
+    /// Returns the recorded state of the onion-tunnel.
+    fn tunnel(&self) -> TunnelState {
+        match self.tunnel.load(Ordering::SeqCst) {
+            s if s == TunnelState::Starting as u8 => TunnelState::Starting,
+            s if s == TunnelState::Running as u8 => TunnelState::Running,
+            _ => TunnelState::Stopped,
+        }
+    }
+
+    /// Answers a single `command` line.
+    fn answer(&self, command: &str) -> Result<String, String> {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        match name {
+            "help" => Ok(format!(
+                "{}; out of scope: {OUT_OF_SCOPE}, {}",
+                COMMANDS.join(" "),
+                MUTATING_COMMANDS.join(" ")
+            )),
+            "pid" => Ok(self.pid.to_string()),
+            "status" => Ok(self.tunnel().name().to_string()),
+            "uptime" => Ok(self.started.elapsed().as_secs().to_string()),
+            "version" => Ok(env!("CARGO_PKG_VERSION").to_string()),
+            name if MUTATING_COMMANDS.contains(&name) => Err(format!(
+                "{name} is not permitted, the control socket is read-only"
+            )),
+            name => Err(format!("unknown command {name:?}, see help")),
+        }
+    }
+}
+
+/// A listening control socket, which is removed from the file system on drop
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlSocket {
+    /// Creates a control socket at `path`, which is only accessible by us.
+    ///
+    /// Relative paths are resolved against `$XDG_RUNTIME_DIR`.  The umask is
+    /// changed temporarily, so this must be called before spawning threads.
+    pub fn bind(path: &Path) -> Result<Self, ControlError> {
+        let path = if path.is_relative() {
+            let runtime_dir =
+                env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| ControlError::NoRuntimeDir {
+                    path: path.to_path_buf(),
+                })?;
+            Path::new(&runtime_dir).join(path)
+        } else {
+            path.to_path_buf()
+        };
+
+        // Create the socket with mode 0600 right away, instead of restricting
+        // it after others could have connected already.
+        let old_mask = umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(&path);
+        umask(old_mask);
+        let listener = listener.map_err(|source| match source.kind() {
+            io::ErrorKind::AddrInUse => ControlError::Exists { path: path.clone() },
+            _ => ControlError::Bind {
+                path: path.clone(),
+                source,
+            },
+        })?;
+        debug!("created control socket at {path:?}");
+
+        Ok(Self { path, listener })
+    }
+
+    /// Returns a handle to the listening socket for use with [`spawn`].
+    pub fn listener(&self) -> io::Result<UnixListener> {
+        self.listener.try_clone()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => debug!("removed control socket at {:?}", self.path),
+            Err(e) => warn!("failed to remove control socket at {:?}: {e}", self.path),
+        }
+    }
+}
+
+/// Answers clients connecting to `listener` about `status` on a thread of its
+/// own, until the process exits.
+///
+/// The thread is independent of the onion-tunnel, so that clients are answered
+/// while it is being created and after it stopped.
+pub fn spawn(listener: UnixListener, status: Arc<Status>) -> io::Result<()> {
+    let runtime = runtime::Builder::new_current_thread().enable_io().build()?;
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || runtime.block_on(serve(listener, status)))?;
+
+    Ok(())
+}
+
+/// Answers clients connecting to `listener` about `status`.
+///
+/// Must be run within a tokio runtime, which it runs in until that shuts down.
+async fn serve(listener: UnixListener, status: Arc<Status>) {
+    let listener = match listener
+        .set_nonblocking(true)
+        .and_then(|()| tokio::net::UnixListener::from_std(listener))
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("failed to serve control socket: {e}");
+            return;
+        }
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle(stream, Arc::clone(&status)));
+            }
+            Err(e) => warn!("failed to accept control connection: {e}"),
+        }
+    }
+}
+
+/// Answers the commands of a single client on `stream` until it disconnects.
+async fn handle(stream: UnixStream, status: Arc<Status>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = match status.answer(&line) {
+            Ok(value) => format!("OK {value}\n"),
+            Err(e) => format!("ERR {e}\n"),
+        };
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn bind_restricts_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control");
+        let socket = ControlSocket::bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(matches!(
+            ControlSocket::bind(&path),
+            Err(ControlError::Exists { .. })
+        ));
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn answer_commands() {
+        let status = Status::new(Pid::from_raw(42));
+        assert_eq!(status.answer("pid"), Ok("42".to_string()));
+        assert!(status
+            .answer("help")
+            .is_ok_and(|help| help.starts_with("help pid status uptime version;")
+                && help.contains("bootstrap progress, traffic counters, exit relay")
+                && help.contains("newnym")));
+        assert_eq!(
+            status.answer("version"),
+            Ok(env!("CARGO_PKG_VERSION").to_string())
+        );
+        assert_eq!(status.answer("uptime"), Ok("0".to_string()));
+
+        assert_eq!(status.answer("status"), Ok("starting".to_string()));
+        status.set_tunnel(TunnelState::Running);
+        assert_eq!(status.answer(" status "), Ok("running".to_string()));
+        status.set_tunnel(TunnelState::Stopped);
+        assert_eq!(status.answer("status"), Ok("stopped".to_string()));
+    }
+
+    #[test]
+    fn spawn_answers_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = ControlSocket::bind(&dir.path().join("control")).unwrap();
+        let status = Arc::new(Status::new(Pid::from_raw(42)));
+        spawn(socket.listener().unwrap(), Arc::clone(&status)).unwrap();
+
+        let mut stream = std::os::unix::net::UnixStream::connect(&socket.path).unwrap();
+        let mut lines = io::BufRead::lines(io::BufReader::new(stream.try_clone().unwrap()));
+        io::Write::write_all(&mut stream, b"status\nshutdown\n").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "OK starting");
+        assert!(lines.next().unwrap().unwrap().starts_with("ERR "));
+    }
+
+    #[test]
+    fn answer_rejects_other_commands() {
+        let status = Status::new(Pid::from_raw(42));
+        assert!(status
+            .answer("shutdown now")
+            .is_err_and(|e| e.contains("read-only")));
+        assert!(status
+            .answer("stauts")
+            .is_err_and(|e| e.contains("unknown")));
+        assert!(status.answer("").is_err());
+    }
+}
@@ -1,166 +1,1794 @@
 #![deny(clippy::unwrap_used)]
 #![deny(clippy::expect_used)]
 use std::{
-    io::Write,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    env,
+    ffi::c_void,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket},
+    num::NonZeroUsize,
     os::{
         fd::{AsRawFd, FromRawFd, OwnedFd},
-        unix::net::UnixDatagram,
+        unix::{
+            fs::symlink,
+            net::UnixDatagram,
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitCode, ExitStatus},
+    ptr::NonNull,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
     },
-    path::PathBuf,
-    process::{Command, ExitCode, ExitStatus},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use caps::CapSet;
-use clap::Parser;
-use log::{debug, error};
+use clap::{
+    parser::ValueSource, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum,
+};
+use log::{debug, error, warn, LevelFilter};
 use netlink_packet_route::AddressFamily;
 use nix::{
+    errno::Errno,
     libc,
+    mount::MsFlags,
     sched::{self, CloneFlags},
-    sys::wait::{self, WaitStatus},
-    unistd::{Gid, Uid},
+    sys::{
+        mman::{self, MapFlags, ProtFlags},
+        prctl,
+        signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        stat::{self, Mode, SFlag},
+        wait::{self, Id, WaitPidFlag, WaitStatus},
+    },
+    unistd::{self, Gid, Pid, SysconfVar, Uid},
 };
 use onion_tunnel::{config::TunnelConfig, scaffolding::LinuxScaffolding, OnionTunnel};
 use sendfd::{RecvWithFd, SendWithFd};
-use smoltcp::phy::{Medium, TunTapInterface};
 use tempfile::NamedTempFile;
-use tokio::runtime::Runtime;
+use tokio::{runtime::Runtime, sync::Notify};
 
+mod config;
+mod control;
 mod mount;
 mod netlink;
+mod seccomp;
+#[cfg(test)]
+mod testutil;
+mod tun;
 mod user;
 
-/// The size of the stacks of our child processes
-const STACK_SIZE: usize = 1000 * 1000 * 8;
+/// The default size of the stacks of our child processes
+const DEFAULT_STACK_SIZE: usize = 1000 * 1000 * 8;
+
+/// The minimum size of the stacks of our child processes
+const MIN_STACK_SIZE: usize = 64 * 1024;
+
+/// The environment variable overriding [`DEFAULT_STACK_SIZE`] in bytes
+const STACK_SIZE_ENV: &str = "ONIUX_STACK_SIZE";
 
 /// The name of the loopback device
 const LOOPBACK_DEVICE: &str = "lo";
 
-/// The name of the TUN device
+/// The default name of the TUN device
 const DEVICE_NAME: &str = "onion0";
 
+/// The default MTU of the TUN device
+const DEFAULT_MTU: u32 = 1500;
+
+/// The hostname inside the UTS namespace, which resolves without `hosts(5)`
+const DEFAULT_HOSTNAME: &str = "localhost";
+
+/// The NIS domain name inside the UTS namespace
+const DEFAULT_DOMAINNAME: &str = "localdomain";
+
+/// The maximum length of a hostname, see `gethostname(2)`
+const HOST_NAME_MAX: usize = 64;
+
+/// The environment variables kept by `--clearenv`
+const KEPT_ENV_VARS: [&str; 3] = ["PATH", "HOME", "TERM"];
+
+/// The default IPv4 address and prefix length of the TUN device
+const TUN_ADDR_V4: (Ipv4Addr, u8) = (Ipv4Addr::new(169, 254, 42, 1), 24);
+
+/// The default IPv6 address and prefix length of the TUN device
+const TUN_ADDR_V6: (Ipv6Addr, u8) = (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x1), 96);
+
+/// The host part of the IPv4 nameserver within the subnet of the TUN device
+const DNS_HOST_V4: u32 = 53;
+
+/// The host part of the IPv6 nameserver within the subnet of the TUN device
+const DNS_HOST_V6: u128 = 0x53;
+
+/// The character devices of a fresh `/dev` as `(name, major, minor)`
+const DEV_NODES: [(&str, u64, u64); 6] = [
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+/// The IPv4 destination routed to by `--self-test`, from TEST-NET-1
+const SELF_TEST_ADDR_V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// The IPv6 destination routed to by `--self-test`, from the documentation
+/// prefix
+const SELF_TEST_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// How long to wait for the parent to report that it is ready by default
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The exit code if the command cannot be found, following the shell
+const EXIT_COMMAND_NOT_FOUND: u8 = 127;
+
+/// How often to check whether the isolation process is still alive while
+/// waiting for the TUN file descriptor
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The exit code if the command exceeded `--timeout`, following `timeout(1)`
+const EXIT_TIMED_OUT: u8 = 124;
+
+/// How long the onion-tunnel may take to shut down once the command exited
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long processes left behind by the command may take to terminate
+const TERMINATION_GRACE: Duration = Duration::from_secs(2);
+
+/// Message telling the isolation process that onion-tunnel is ready
+const MSG_READY: u8 = 0;
+
+/// Message, followed by an error string, telling the isolation process that
+/// onion-tunnel could not be created
+const MSG_FAILED: u8 = 1;
+
+/// The exit code if the TUN kernel module is missing or inaccessible,
+/// `EX_UNAVAILABLE` from `sysexits.h`
+const EXIT_TUN_UNAVAILABLE: u8 = 69;
+
+/// The exit code if the parent did not report in time that onion-tunnel is
+/// ready, `EX_TEMPFAIL` from `sysexits.h`
+const EXIT_NOT_READY: u8 = 75;
+
+/// The exit code if the onion-tunnel terminated while the command was running,
+/// `EX_SOFTWARE` from `sysexits.h`
+const EXIT_TUNNEL_FAILED: u8 = 70;
+
+/// The signals that are relayed to the isolated command
+const RELAYED_SIGNALS: [Signal; 4] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGQUIT,
+];
+
+/// The process to which [`relay_signal()`] forwards signals, or zero if unset
+static RELAY_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Whether a repeated SIGINT gets escalated to SIGKILL
+static ESCALATE: AtomicBool = AtomicBool::new(false);
+
+/// Whether a SIGINT has been received before
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the onion-tunnel terminated and the command got killed because of it
+static TUNNEL_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the onion-tunnel is being shut down, because the command exited
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// What to do if the onion-tunnel terminates while the command is running
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TunnelFailure {
+    /// Keep the command running, even though its traffic goes nowhere
+    Ignore,
+    /// Kill the command and exit with a distinct exit code
+    Kill,
+}
+
+/// The format of the log on stderr
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with timestamp, level, target and message
+    Json,
+}
+
 #[derive(Parser, Debug)]
 struct Args {
+    /// Read options from this configuration file
+    ///
+    /// Defaults to $XDG_CONFIG_HOME/oniux/config.toml if it exists. Options
+    /// given on the command line take precedence.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Increase the verbosity of oniux, may be given up to three times
+    ///
+    /// Dependencies only log warnings and errors. The RUST_LOG environment
+    /// variable takes precedence if set.
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// The format of the log
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Nameserver to use instead of the one provided by onionmasq
+    ///
+    /// May be given multiple times.
+    #[arg(long, visible_alias = "nameserver", value_name = "IP")]
+    dns: Vec<IpAddr>,
+
+    /// Domain to add to the search list of the resolv.conf(5)
+    ///
+    /// May be given multiple times.
+    #[arg(long, value_name = "DOMAIN", value_parser = parse_search_domain)]
+    resolv_search: Vec<String>,
+
+    /// Do not override /etc/resolv.conf within the namespace
+    ///
+    /// WARNING: DNS queries will then go to the nameservers of the host, which
+    /// may leak them outside of Tor, e.g. if name resolution is handed over to
+    /// a host daemon such as systemd-resolved via IPC.
+    #[arg(long, alias = "no-resolv-conf", conflicts_with_all = ["dns", "resolv_search"])]
+    keep_resolv_conf: bool,
+
+    /// The IPv4 address of the nameserver provided by onionmasq
+    ///
+    /// Must lie within the IPv4 subnet of the TUN device.  Defaults to the host
+    /// part 53 of that subnet, i.e. 169.254.42.53, or to one of its last hosts
+    /// if the subnet is too small.
+    #[arg(
+        long,
+        value_name = "IP",
+        conflicts_with_all = ["dns", "keep_resolv_conf", "ipv6_only"]
+    )]
+    dns4: Option<Ipv4Addr>,
+
+    /// The IPv6 address of the nameserver provided by onionmasq
+    ///
+    /// Must lie within the IPv6 subnet of the TUN device.  Defaults to the host
+    /// part ::53 of that subnet, i.e. fe80::53, or to one of its last hosts if
+    /// the subnet is too small.
+    #[arg(
+        long,
+        value_name = "IP",
+        conflicts_with_all = ["dns", "keep_resolv_conf", "ipv4_only"]
+    )]
+    dns6: Option<Ipv6Addr>,
+
+    /// The IPv4 address and prefix length of the TUN device
+    ///
+    /// Useful if the command needs to reach link-local services in the default
+    /// 169.254.42.1/24 from within the namespace.
+    #[arg(
+        long,
+        value_name = "CIDR",
+        value_parser = parse_tun_ipv4,
+        conflicts_with = "ipv6_only"
+    )]
+    tun_ipv4: Option<(Ipv4Addr, u8)>,
+
+    /// The IPv6 address and prefix length of the TUN device
+    ///
+    /// Defaults to fe80::1/96.
+    #[arg(
+        long,
+        value_name = "CIDR",
+        value_parser = parse_tun_ipv6,
+        conflicts_with = "ipv4_only"
+    )]
+    tun_ipv6: Option<(Ipv6Addr, u8)>,
+
+    /// Only configure IPv4 within the namespace
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Only configure IPv6 within the namespace
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Map the caller to root (UID and GID 0) within the namespace
+    ///
+    /// The command is still unprivileged on the host and `setgroups(2)`
+    /// remains denied, as is required for unprivileged GID mappings.
+    #[arg(long)]
+    map_root: bool,
+
+    /// The outer UID and GID to map to instead of those of the caller
+    ///
+    /// The GID defaults to the one of the caller. Mapping other IDs requires
+    /// the respective privileges, e.g. when run from a setuid wrapper.
+    /// Subordinate IDs are not mapped then.
+    #[arg(long, value_name = "UID[:GID]", value_parser = parse_user)]
+    user: Option<(Uid, Option<Gid>)>,
+
+    /// The name of the TUN device
+    #[arg(
+        short = 'n',
+        long,
+        visible_aliases = ["name", "tun-name"],
+        value_name = "NAME",
+        default_value = DEVICE_NAME,
+        value_parser = parse_device_name
+    )]
+    device_name: String,
+
+    /// The MTU of the TUN device
+    ///
+    /// Must be between 1280 and 65535.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_MTU,
+        value_parser = clap::value_parser!(u32).range(netlink::MIN_MTU as i64..=netlink::MAX_MTU as i64)
+    )]
+    mtu: u32,
+
+    /// The hostname seen by the command
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = DEFAULT_HOSTNAME,
+        value_parser = parse_hostname
+    )]
+    hostname: String,
+
+    /// Share the UTS namespace, and thus the hostname, with the host
+    #[arg(long, conflicts_with = "hostname")]
+    no_uts_namespace: bool,
+
+    /// Only map the own UID and GID, ignoring /etc/subuid and /etc/subgid
+    ///
+    /// By default, the subordinate IDs of the user are mapped around the own
+    /// ones using newuidmap(1) and newgidmap(1), if there are any.
+    #[arg(long)]
+    no_subuid: bool,
+
+    /// Share the PID namespace with the host
+    ///
+    /// Allows the command to see and signal host processes. The host's /proc
+    /// is kept, as a fresh procfs can only be mounted in a new PID namespace.
+    /// Processes left behind by the command are no longer killed when it
+    /// exits. Network isolation is unaffected by this.
+    #[arg(long)]
+    no_pid_namespace: bool,
+
+    /// Bind mount SRC of the host onto DST within the namespace
+    ///
+    /// Both paths must be absolute and DST must exist. May be given multiple
+    /// times.
+    #[arg(long, value_name = "SRC:DST", value_parser = parse_bind)]
+    bind: Vec<(PathBuf, PathBuf)>,
+
+    /// Like --bind, but read-only
+    #[arg(long, value_name = "SRC:DST", value_parser = parse_bind)]
+    bind_ro: Vec<(PathBuf, PathBuf)>,
+
+    /// Use DIR as the root file system of the command
+    ///
+    /// Paths given to --bind and --bind-ro are mounted into it, with their
+    /// sources taken from the host. DIR needs to contain /proc, /sys, /tmp
+    /// and /etc/resolv.conf, and devices have to be bound into it, e.g. with
+    /// --bind /dev:/dev.
+    #[arg(
+        long,
+        value_name = "DIR",
+        value_parser = parse_rootfs,
+        conflicts_with_all = ["no_pid_namespace", "share_sys", "share_tmp", "overlay", "fresh_dev"]
+    )]
+    rootfs: Option<PathBuf>,
+
+    /// Make changes to a directory ephemeral by overlaying it with a tmpfs
+    ///
+    /// May be given multiple times. Changes are discarded once the command
+    /// exits. Requires Linux 5.11 or newer.
+    #[arg(long, value_name = "DIR", value_parser = parse_overlay)]
+    overlay: Vec<PathBuf>,
+
+    /// Share /tmp with the host instead of mounting an empty tmpfs on it
+    ///
+    /// Needed by programs that talk to host processes over sockets in /tmp,
+    /// such as X11 clients using /tmp/.X11-unix.
+    #[arg(long)]
+    share_tmp: bool,
+
+    /// Keep the /sys of the host instead of mounting a fresh sysfs on it
+    ///
+    /// This is also done, with a warning, where the kernel refuses to mount
+    /// sysfs, e.g. if parts of the host's /sys are masked, as in many
+    /// containers. Host network interfaces are then visible in /sys/class/net.
+    #[arg(long)]
+    share_sys: bool,
+
+    /// Mount a fresh /dev with only a minimal set of devices
+    ///
+    /// Hides the devices of the host, such as disks and GPUs, from the
+    /// command. Programs that need other devices, e.g. pseudo terminals in
+    /// /dev/pts, will not work.
+    #[arg(long)]
+    fresh_dev: bool,
+
+    /// Share the IPC namespace with the host
+    ///
+    /// Needed by programs that talk to host processes over System V IPC or
+    /// POSIX message queues, at the cost of weaker isolation.
+    #[arg(long, alias = "no-ipc-ns")]
+    share_ipc: bool,
+
+    /// What to do if the onion-tunnel terminates while the command is running
+    #[arg(long, value_enum, default_value_t = TunnelFailure::Kill)]
+    on_tunnel_failure: TunnelFailure,
+
+    /// Log the connections made through the tunnel
+    ///
+    /// Beware that this records sensitive metadata about the destinations
+    /// reached by the command in the log. Like all logging, it goes to
+    /// stderr and is shown even with --quiet.
+    #[arg(long)]
+    log_connections: bool,
+
+    /// Restrict the system calls of the command with seccomp(2)
+    ///
+    /// PROFILE is either `deny-dangerous`, which denies system calls modifying
+    /// the kernel or system-wide state, leaving the namespaces or tampering
+    /// with other processes, such as mount(2), setns(2) or ptrace(2), but
+    /// allows all others, or the path of a seccompiler JSON file with a filter
+    /// named `command`.
+    ///
+    /// The filter is applied to oniux within the namespace once it has set up
+    /// the network, so a custom filter must also allow spawning and waiting for
+    /// the command.
+    #[arg(long, value_name = "PROFILE", value_parser = parse_seccomp)]
+    seccomp: Option<seccomp::Profile>,
+
+    /// Terminate the command after this duration, e.g. 90s or 5m
+    ///
+    /// The command is killed if it does not exit within a short grace period
+    /// after SIGTERM. oniux then exits with 124, like timeout(1).
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// How long to wait for onion-tunnel to be ready before giving up
+    ///
+    /// onion-tunnel is ready once it has been created on the TUN device, which
+    /// does not mean that Tor has finished bootstrapping. oniux exits with 75
+    /// if it is not ready in time.
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_timeout)]
+    ready_timeout: Duration,
+
+    /// Run the command without waiting for onion-tunnel to be ready
+    ///
+    /// Connections made by the command before are likely to fail.
+    #[arg(long, conflicts_with_all = ["ready_timeout", "self_test"])]
+    no_wait: bool,
+
+    /// Only use exit relays in the country with this ISO 3166 code, e.g. DE
+    ///
+    /// Connections fail if no such exit relay is available.
+    #[arg(long, value_name = "CC", value_parser = parse_country_code)]
+    exit_country: Option<String>,
+
+    /// Answer read-only status queries on a Unix domain socket at PATH
+    ///
+    /// Relative paths are resolved against $XDG_RUNTIME_DIR. Clients send one
+    /// command per line, e.g. `status`, `pid`, `uptime`, `version` or `help`,
+    /// and receive a line starting with `OK` or `ERR` in return.  The status
+    /// is `starting` until onion-tunnel has been created, `running` while it
+    /// runs and `stopped` afterwards; Tor's bootstrap progress is not reported.
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Run the command in this working directory
+    ///
+    /// The path is interpreted inside the namespace, after all mounts have
+    /// been applied.
+    #[arg(long, visible_alias = "chdir", value_name = "PATH")]
+    workdir: Option<PathBuf>,
+
+    /// Start the command with an empty environment
+    ///
+    /// PATH, HOME and TERM are kept, unless removed with --unsetenv.
+    #[arg(long, aliases = ["clear-env", "env-clear"])]
+    clearenv: bool,
+
+    /// Keep an environment variable despite --clearenv
+    ///
+    /// May be given multiple times.
+    #[arg(long, alias = "env-keep", value_name = "KEY", requires = "clearenv")]
+    keepenv: Vec<String>,
+
+    /// Remove an environment variable of the command
+    ///
+    /// May be given multiple times. Applied after --clearenv.
+    #[arg(long, alias = "unset", value_name = "KEY")]
+    unsetenv: Vec<String>,
+
+    /// Set an environment variable of the command
+    ///
+    /// May be given multiple times. Applied after --clearenv and --unsetenv.
+    #[arg(long, alias = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    setenv: Vec<(String, String)>,
+
+    /// Check the isolation instead of running a command
+    ///
+    /// Prints the results in the Test Anything Protocol and exits with 1 if
+    /// any check failed.
+    #[arg(long, conflicts_with_all = ["cmd", "cmd_file"])]
+    self_test: bool,
+
+    /// Read the program and its arguments from a file, or stdin if `-`
+    ///
+    /// The arguments are separated by NUL bytes if there are any, by newlines
+    /// otherwise.
+    #[arg(long, value_name = "PATH", conflicts_with = "cmd")]
+    cmd_file: Option<PathBuf>,
+
     /// The actual program to execute
-    #[arg(trailing_var_arg = true, required = true)]
+    #[arg(
+        trailing_var_arg = true,
+        required_unless_present_any = ["self_test", "cmd_file"]
+    )]
     cmd: Vec<String>,
 }
 
+/// Validate `name` as a network interface name
+fn parse_device_name(name: &str) -> Result<String, String> {
+    if name.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(format!(
+            "must not be longer than {} bytes",
+            libc::IFNAMSIZ - 1
+        ));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("must not be {name:?}"));
+    }
+    if name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+        return Err("must not contain '/', ':' or whitespace".to_string());
+    }
+
+    Ok(name.to_string())
+}
+
+/// Validate `domain` as an entry of the `search` option in `resolv.conf(5)`
+fn parse_search_domain(domain: &str) -> Result<String, String> {
+    if domain.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if domain.contains(char::is_whitespace) {
+        return Err("must not contain whitespace".to_string());
+    }
+
+    Ok(domain.to_string())
+}
+
+/// Validate `duration` as a timeout, which must be longer than zero
+fn parse_timeout(duration: &str) -> Result<Duration, String> {
+    let duration = humantime::parse_duration(duration).map_err(|e| format!("{e}"))?;
+    if duration.is_zero() {
+        return Err("must be longer than zero".to_string());
+    }
+
+    Ok(duration)
+}
+
+/// Validate `cidr` as the IPv4 address and prefix length of the TUN device
+fn parse_tun_ipv4(cidr: &str) -> Result<(Ipv4Addr, u8), String> {
+    // Leave room for the nameserver next to the TUN device.
+    parse_cidr(cidr, 30)
+}
+
+/// Validate `cidr` as the IPv6 address and prefix length of the TUN device
+fn parse_tun_ipv6(cidr: &str) -> Result<(Ipv6Addr, u8), String> {
+    parse_cidr(cidr, 126)
+}
+
+/// Parse `cidr` as `ADDR/PREFIX_LEN` with a prefix length of at most
+/// `max_prefix_len`
+fn parse_cidr<A: FromStr>(cidr: &str, max_prefix_len: u8) -> Result<(A, u8), String>
+where
+    A::Err: Display,
+{
+    let (addr, prefix_len) = cidr
+        .split_once('/')
+        .ok_or_else(|| "must be of the form ADDR/PREFIX_LEN".to_string())?;
+    let addr = addr.parse().map_err(|e| format!("{e}"))?;
+    let prefix_len: u8 = prefix_len
+        .parse()
+        .map_err(|e| format!("invalid prefix length: {e}"))?;
+    if !(1..=max_prefix_len).contains(&prefix_len) {
+        return Err(format!(
+            "prefix length must be between 1 and {max_prefix_len}"
+        ));
+    }
+
+    Ok((addr, prefix_len))
+}
+
+/// Validate `name` as a hostname accepted by `sethostname(2)`
+fn parse_hostname(name: &str) -> Result<String, String> {
+    if name.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if name.len() > HOST_NAME_MAX {
+        return Err(format!("must not be longer than {HOST_NAME_MAX} bytes"));
+    }
+
+    Ok(name.to_string())
+}
+
+/// Validate `dir` as an existing directory, made absolute
+fn parse_rootfs(dir: &str) -> Result<PathBuf, String> {
+    let dir = fs::canonicalize(dir).map_err(|e| format!("{dir:?}: {e}"))?;
+    if !dir.is_dir() {
+        return Err(format!("{dir:?} is not a directory"));
+    }
+
+    Ok(dir)
+}
+
+/// Validate `dir` as an absolute directory usable as lower layer of an overlay
+fn parse_overlay(dir: &str) -> Result<PathBuf, String> {
+    // These characters would need escaping in the mount options.
+    if dir.contains([',', ':', '\\']) {
+        return Err("must not contain ',', ':' or '\\'".to_string());
+    }
+    let dir = PathBuf::from(dir);
+    if !dir.is_absolute() {
+        return Err("must be absolute".to_string());
+    }
+    if !dir.is_dir() {
+        return Err(format!("{dir:?} is not an existing directory"));
+    }
+
+    Ok(dir)
+}
+
+/// Validate `cc` as a two-letter country code, normalized to upper case
+fn parse_country_code(cc: &str) -> Result<String, String> {
+    if cc.len() != 2 || !cc.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err("must be a two-letter country code".to_string());
+    }
+
+    Ok(cc.to_ascii_uppercase())
+}
+
+/// Parse `profile` as `deny-dangerous` or the path of a seccomp policy file
+fn parse_seccomp(profile: &str) -> Result<seccomp::Profile, String> {
+    if profile == "deny-dangerous" {
+        return Ok(seccomp::Profile::DenyDangerous);
+    }
+    let path = PathBuf::from(profile);
+    if !path.is_file() {
+        return Err("must be `deny-dangerous` or the path of an existing file".to_string());
+    }
+
+    Ok(seccomp::Profile::File(path))
+}
+
+/// Parse `user` of the form `UID[:GID]`
+fn parse_user(user: &str) -> Result<(Uid, Option<Gid>), String> {
+    let (uid, gid) = match user.split_once(':') {
+        Some((uid, gid)) => (uid, Some(gid)),
+        None => (user, None),
+    };
+    let uid = uid
+        .parse()
+        .map(Uid::from_raw)
+        .map_err(|e| format!("invalid UID {uid:?}: {e}"))?;
+    let gid = gid
+        .map(|gid| {
+            gid.parse()
+                .map(Gid::from_raw)
+                .map_err(|e| format!("invalid GID {gid:?}: {e}"))
+        })
+        .transpose()?;
+
+    Ok((uid, gid))
+}
+
+/// Parse `bind` of the form `SRC:DST` into absolute paths, with `SRC` existing
+fn parse_bind(bind: &str) -> Result<(PathBuf, PathBuf), String> {
+    let Some((src, dst)) = bind.split_once(':') else {
+        return Err("must be of the form SRC:DST".to_string());
+    };
+    let (src, dst) = (PathBuf::from(src), PathBuf::from(dst));
+    if !src.is_absolute() || !dst.is_absolute() {
+        return Err("both paths must be absolute".to_string());
+    }
+    if !src.exists() {
+        return Err(format!("source {src:?} does not exist"));
+    }
+
+    Ok((src, dst))
+}
+
+/// Parse `var` of the form `KEY=VALUE` into its key and value
+fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("must be of the form KEY=VALUE".to_string()),
+    }
+}
+
+/// The addressing within the network namespace
+///
+/// Drives both the addresses of the TUN device and the nameservers written to
+/// the `resolv.conf(5)`, which have to lie within the subnets of the former in
+/// order to be routed to onionmasq.
+struct NetConfig {
+    tun_v4: (Ipv4Addr, u8),
+    tun_v6: (Ipv6Addr, u8),
+    dns_v4: Ipv4Addr,
+    dns_v6: Ipv6Addr,
+}
+
+impl NetConfig {
+    /// Creates the addressing according to `--tun-ipv4`, `--tun-ipv6`,
+    /// `--dns4` and `--dns6`.
+    ///
+    /// Nameservers which are not given explicitly are derived from the subnets
+    /// of the TUN device using [`nameserver_host`].
+    fn new(args: &Args) -> Result<Self> {
+        let tun_v4 = args.tun_ipv4.unwrap_or(TUN_ADDR_V4);
+        let tun_v6 = args.tun_ipv6.unwrap_or(TUN_ADDR_V6);
+        let dns_v4 = args.dns4.unwrap_or_else(|| {
+            let host_mask = u32::MAX >> tun_v4.1;
+            let tun = u32::from(tun_v4.0);
+            let host = nameserver_host(
+                DNS_HOST_V4.into(),
+                (tun & host_mask).into(),
+                host_mask.into(),
+            );
+            // The host part is covered by `host_mask` and hence fits.
+            Ipv4Addr::from(tun & !host_mask | host as u32)
+        });
+        let dns_v6 = args.dns6.unwrap_or_else(|| {
+            let host_mask = u128::MAX >> tun_v6.1;
+            let tun = u128::from(tun_v6.0);
+            let host = nameserver_host(DNS_HOST_V6, tun & host_mask, host_mask);
+            Ipv6Addr::from(tun & !host_mask | host)
+        });
+
+        check_nameserver(IpAddr::V4(dns_v4), IpAddr::V4(tun_v4.0), tun_v4.1)
+            .map_err(|e| anyhow!("{e}, see --tun-ipv4 and --dns4"))?;
+        check_nameserver(IpAddr::V6(dns_v6), IpAddr::V6(tun_v6.0), tun_v6.1)
+            .map_err(|e| anyhow!("{e}, see --tun-ipv6 and --dns6"))?;
+
+        Ok(Self {
+            tun_v4,
+            tun_v6,
+            dns_v4,
+            dns_v6,
+        })
+    }
+
+    /// The addresses of the TUN device as `(addr, prefix_len)` pairs
+    fn tun_addrs(&self) -> [(IpAddr, u8); 2] {
+        [
+            (IpAddr::V4(self.tun_v4.0), self.tun_v4.1),
+            (IpAddr::V6(self.tun_v6.0), self.tun_v6.1),
+        ]
+    }
+
+    /// The nameservers provided by onionmasq
+    fn nameservers(&self) -> [IpAddr; 2] {
+        [IpAddr::V4(self.dns_v4), IpAddr::V6(self.dns_v6)]
+    }
+}
+
+/// Picks the host part of a nameserver next to the host part `tun` of the TUN
+/// device, within a subnet whose host parts are covered by `host_mask`
+///
+/// Prefers `preferred` and falls back to the last hosts, before the broadcast
+/// address, for subnets too small to hold it.
+fn nameserver_host(preferred: u128, tun: u128, host_mask: u128) -> u128 {
+    // The prefix lengths accepted by `parse_cidr` leave at least two hosts, so
+    // one of the last two always differs from `tun`.
+    [preferred, host_mask - 1, host_mask - 2]
+        .into_iter()
+        .find(|&host| host != 0 && host < host_mask && host != tun)
+        .unwrap_or(preferred)
+}
+
+/// Checks that the nameserver `dns` lies within the subnet `tun/prefix_len` of
+/// the TUN device without being its address, so that onionmasq answers it.
+fn check_nameserver(dns: IpAddr, tun: IpAddr, prefix_len: u8) -> Result<()> {
+    // Compare IPv4 addresses as IPv4-mapped IPv6 addresses.
+    let widen = |addr: IpAddr| match addr {
+        IpAddr::V4(addr) => (u128::from(addr.to_ipv6_mapped()), 96),
+        IpAddr::V6(addr) => (u128::from(addr), 0),
+    };
+    let (dns_bits, _) = widen(dns);
+    let (tun_bits, offset) = widen(tun);
+    let mask = u128::MAX << (128 - (offset + prefix_len));
+    if dns_bits & mask != tun_bits & mask {
+        bail!("nameserver {dns} does not lie within {tun}/{prefix_len}");
+    }
+    if dns == tun {
+        bail!("nameserver {dns} must not be the address of the TUN device");
+    }
+
+    Ok(())
+}
+
+/// A stack for calls to `clone(2)` with an inaccessible guard page below it,
+/// so that overflowing it results in a `SIGSEGV` instead of memory corruption
+struct Stack {
+    ptr: NonNull<c_void>,
+    guard: usize,
+    size: usize,
+}
+
+impl Stack {
+    /// Maps a stack of `size` bytes, rounded up to whole pages.
+    fn new(size: usize) -> Result<Self> {
+        let page = unistd::sysconf(SysconfVar::PAGE_SIZE)?
+            .and_then(|page| usize::try_from(page).ok())
+            .ok_or_else(|| anyhow!("failed to determine the page size"))?;
+        let size = size.div_ceil(page) * page;
+        let len = NonZeroUsize::new(page + size).ok_or_else(|| anyhow!("empty stack"))?;
+
+        // SAFETY: A fresh anonymous mapping does not alias any other memory.
+        let ptr = unsafe {
+            mman::mmap_anonymous(
+                None,
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_STACK,
+            )?
+        };
+        let stack = Self {
+            ptr,
+            guard: page,
+            size,
+        };
+        // SAFETY: The guard page lies within the mapping and is never accessed.
+        unsafe { mman::mprotect(ptr, page, ProtFlags::PROT_NONE)? };
+
+        Ok(stack)
+    }
+
+    /// The usable part of the stack above the guard page
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: The mapping is readable, writable and owned by `self`.
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.ptr.as_ptr().cast::<u8>().add(self.guard),
+                self.size,
+            )
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // SAFETY: The mapping is not referenced anymore once `self` is dropped.
+        if let Err(e) = unsafe { mman::munmap(self.ptr, self.guard + self.size) } {
+            warn!("failed to unmap stack: {e}");
+        }
+    }
+}
+
 /// Generate an empty stack for calls to `clone(2)`
-fn gen_stack() -> Vec<u8> {
-    vec![0u8; STACK_SIZE]
+///
+/// Its size may be overridden with [`STACK_SIZE_ENV`].
+fn gen_stack() -> Result<Stack> {
+    let size = match env::var(STACK_SIZE_ENV) {
+        Ok(size) => {
+            let size: usize = size
+                .parse()
+                .with_context(|| format!("invalid {STACK_SIZE_ENV} {size:?}"))?;
+            if size < MIN_STACK_SIZE {
+                bail!("{STACK_SIZE_ENV} must be at least {MIN_STACK_SIZE} bytes");
+            }
+            size
+        }
+        Err(env::VarError::NotPresent) => DEFAULT_STACK_SIZE,
+        Err(e) => bail!("invalid {STACK_SIZE_ENV}: {e}"),
+    };
+    let stack = Stack::new(size)?;
+    debug!("allocated a stack of {} bytes", stack.size);
+
+    Ok(stack)
 }
 
-fn isolation(parent: UnixDatagram, uid: Uid, gid: Gid, cmd: &[String]) -> Result<ExitStatus> {
+/// Generate the contents of a `resolv.conf(5)` pointing to `nameservers`
+///
+/// If `search` is not empty, a `search` option listing its domains is added.
+fn gen_resolv_conf(nameservers: &[IpAddr], search: &[String]) -> String {
+    let mut resolv_conf: String = nameservers
+        .iter()
+        .map(|ns| format!("nameserver {ns}\n"))
+        .collect();
+    if !search.is_empty() {
+        resolv_conf.push_str(&format!("search {}\n", search.join(" ")));
+    }
+
+    resolv_conf
+}
+
+/// Forwards `sig` to `proc`.
+///
+/// SIGKILL and SIGSTOP cannot be caught in the first place and SIGCHLD only
+/// concerns ourselves, hence they are silently ignored.
+fn forward_signal(proc: Pid, sig: Signal) {
+    if matches!(sig, Signal::SIGKILL | Signal::SIGSTOP | Signal::SIGCHLD) {
+        return;
+    }
+
+    // There is nothing sensible to do about an error within a signal handler.
+    let _ = signal::kill(proc, sig);
+}
+
+/// Signal handler forwarding `sig` to [`RELAY_PID`].
+///
+/// Signals generated by the terminal, such as the SIGINT caused by Ctrl-C, are
+/// delivered to the entire foreground process group, which already contains
+/// the command; hence, only signals sent explicitly, e.g. by `kill(1)`, are
+/// forwarded in order to not deliver them twice.
+///
+/// Keep in mind that only async-signal-safe functions may be called in here.
+extern "C" fn relay_signal(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let pid = RELAY_PID.load(Ordering::SeqCst);
+    let Ok(sig) = Signal::try_from(sig) else {
+        return;
+    };
+    if pid == 0 {
+        return;
+    }
+    let proc = Pid::from_raw(pid);
+
+    // Killing the init process of a PID namespace kills everything within it.
+    if sig == Signal::SIGINT
+        && ESCALATE.load(Ordering::SeqCst)
+        && INTERRUPTED.swap(true, Ordering::SeqCst)
+    {
+        let _ = signal::kill(proc, Signal::SIGKILL);
+        return;
+    }
+
+    // The kernel always passes a valid `siginfo_t` to `SA_SIGINFO` handlers.
+    if unsafe { (*info).si_code } != libc::SI_KERNEL {
+        forward_signal(proc, sig);
+    }
+}
+
+/// Relays all [`RELAYED_SIGNALS`] received by this process to `pid`.
+///
+/// A `pid` of -1 relays them to all processes this process may signal, which
+/// are all other processes of the PID namespace if this is its init process.
+///
+/// If `escalate` is set, a second SIGINT will be turned into a SIGKILL, so that
+/// hitting Ctrl-C twice always terminates `pid`.
+fn relay_signals(pid: Pid, escalate: bool) -> Result<()> {
+    RELAY_PID.store(pid.as_raw(), Ordering::SeqCst);
+    ESCALATE.store(escalate, Ordering::SeqCst);
+
+    let action = SigAction::new(
+        SigHandler::SigAction(relay_signal),
+        SaFlags::SA_SIGINFO | SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for sig in RELAYED_SIGNALS {
+        unsafe { signal::sigaction(sig, &action) }?;
+    }
+    debug!("relaying signals to {pid}");
+
+    Ok(())
+}
+
+/// Overwrites `/etc/resolv.conf` with a bind mount to use the nameservers
+/// provided by onionmasq, unless the user supplied their own.
+///
+/// The returned file backs the bind mount and must be kept around for as long
+/// as the command runs.
+fn override_resolv_conf(args: &Args, net: &NetConfig) -> Result<NamedTempFile> {
+    let nameservers: Vec<_> = if args.dns.is_empty() {
+        net.nameservers()
+            .into_iter()
+            .filter(|ns| family_enabled(args, ns))
+            .collect()
+    } else {
+        args.dns.clone()
+    };
+    let mut resolv_conf = NamedTempFile::new()?;
+    resolv_conf.write_all(gen_resolv_conf(&nameservers, &args.resolv_search).as_bytes())?;
+    debug!(
+        "created temporary resolv.conf(5) at {:?}",
+        resolv_conf.path()
+    );
+    mount::bind(resolv_conf.path(), &PathBuf::from("/etc/resolv.conf"))?;
+    debug!("mounted {:?} to /etc/resolv.conf", resolv_conf.path());
+
+    Ok(resolv_conf)
+}
+
+/// Mounts a fresh `/dev` containing only the [`DEV_NODES`] and the usual
+/// links into `/proc/self/fd`.
+///
+/// The device nodes are created with `mknod(2)` where permitted, otherwise,
+/// as within a `user_namespaces(7)`, they are bind mounted from the host. The
+/// new `/dev` is therefore prepared aside and only then moved over the host's.
+fn fresh_dev() -> Result<()> {
+    let staging = tempfile::tempdir()?;
+    mount::devtmpfs(staging.path())?;
+
+    for (name, major, minor) in DEV_NODES {
+        let node = staging.path().join(name);
+        let host = Path::new("/dev").join(name);
+        match stat::mknod(
+            &node,
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(0o666),
+            stat::makedev(major, minor),
+        ) {
+            Ok(()) => debug!("created device node {node:?}"),
+            // Already populated by a real `devtmpfs`.
+            Err(Errno::EEXIST) => {}
+            Err(Errno::EPERM) if host.exists() => {
+                File::create(&node)?;
+                mount::bind(&host, &node)?;
+            }
+            Err(Errno::EPERM) => warn!("{host:?} does not exist, omitting it"),
+            Err(e) => return Err(e).with_context(|| format!("failed to create {node:?}")),
+        }
+    }
+    for (name, target) in [
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ] {
+        let link = staging.path().join(name);
+        if !link.exists() {
+            symlink(target, link)?;
+        }
+    }
+
+    mount::move_mount(staging.path(), Path::new("/dev"))?;
+    debug!("mounted a fresh /dev");
+
+    Ok(())
+}
+
+/// Overlays `dir` with a fresh `tmpfs`, so that changes to it are discarded.
+///
+/// The `tmpfs` is detached again right away, as overlayfs keeps its own
+/// reference to it.
+fn ephemeral(dir: &Path) -> Result<()> {
+    let staging = tempfile::tempdir()?;
+    mount::tmpfs(staging.path(), None)?;
+    let (upper, work) = (staging.path().join("upper"), staging.path().join("work"));
+    fs::create_dir(&upper)?;
+    fs::create_dir(&work)?;
+    // The root of the overlay takes its mode from the upper directory.
+    fs::set_permissions(&upper, fs::metadata(dir)?.permissions())?;
+
+    mount::overlay(dir, &upper, &work, dir)?;
+    mount::detach(staging.path())?;
+
+    Ok(())
+}
+
+/// Checks whether the command of `args` exists and is executable, looking it
+/// up in the PATH it will be run with, like `which(1)`.
+///
+/// Relative paths are only checked without `--workdir`, as they would
+/// otherwise refer to a directory inside the namespace.
+fn command_exists(args: &Args) -> bool {
+    let is_executable =
+        |path: &Path| path.is_file() && unistd::access(path, unistd::AccessFlags::X_OK).is_ok();
+
+    let cmd = Path::new(&args.cmd[0]);
+    if args.cmd[0].contains('/') {
+        return (args.workdir.is_some() && cmd.is_relative()) || is_executable(cmd);
+    }
+
+    let path = match args.setenv.iter().rev().find(|(key, _)| key == "PATH") {
+        Some((_, value)) => Some(value.into()),
+        None if args.unsetenv.iter().any(|key| key == "PATH") => None,
+        None => env::var_os("PATH"),
+    };
+    match path {
+        Some(path) => env::split_paths(&path).any(|dir| is_executable(&dir.join(cmd))),
+        // Without a PATH, the lookup is left to the standard library.
+        None => true,
+    }
+}
+
+/// Whether the address family of `addr` is configured within the namespace
+fn family_enabled(args: &Args, addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(_) => !args.ipv6_only,
+        IpAddr::V6(_) => !args.ipv4_only,
+    }
+}
+
+/// Build the [`Command`] to run, including its environment.
+///
+/// Without a PID namespace, the command is killed along with the isolation
+/// process, e.g. on a second SIGINT, instead of being orphaned on the host.
+fn command(args: &Args) -> Command {
+    let mut cmd = Command::new(&args.cmd[0]);
+    cmd.args(&args.cmd[1..]);
+    if args.no_pid_namespace {
+        let isolation = unistd::getpid();
+        // SAFETY: Only async-signal-safe system calls are performed.
+        unsafe {
+            cmd.pre_exec(move || {
+                prctl::set_pdeathsig(Signal::SIGKILL)?;
+                // The isolation process may have died before the above.
+                if unistd::getppid() != isolation {
+                    return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                }
+                Ok(())
+            });
+        }
+    }
+    if let Some(workdir) = &args.workdir {
+        cmd.current_dir(workdir);
+    }
+
+    if args.clearenv {
+        cmd.env_clear();
+        let kept = KEPT_ENV_VARS
+            .iter()
+            .copied()
+            .chain(args.keepenv.iter().map(String::as_str));
+        for key in kept {
+            if let Some(value) = env::var_os(key) {
+                cmd.env(key, value);
+            }
+        }
+    }
+    for key in &args.unsetenv {
+        cmd.env_remove(key);
+    }
+    cmd.envs(args.setenv.iter().map(|(key, value)| (key, value)));
+
+    cmd
+}
+
+/// Returns the UID and GID as seen inside the namespace.
+fn inner_ids(args: &Args, uid: Uid, gid: Gid) -> (Uid, Gid) {
+    if args.map_root {
+        (Uid::from_raw(0), Gid::from_raw(0))
+    } else {
+        (uid, gid)
+    }
+}
+
+/// Builds the UID and GID mappings including the subordinate IDs of `uid`.
+///
+/// Returns [`None`] if `uid` has no subordinate IDs.
+fn subid_maps(args: &Args, uid: Uid, gid: Gid) -> Result<Option<SubidMaps>> {
+    let (inner_uid, inner_gid) = inner_ids(args, uid, gid);
+    let uid_map = user::uid_map_from_subuid(inner_uid, uid)?;
+    let gid_map = user::gid_map_from_subgid(inner_gid, uid, gid)?;
+    if uid_map.len() == 1 && gid_map.len() == 1 {
+        return Ok(None);
+    }
+
+    Ok(Some((uid_map, gid_map)))
+}
+
+fn isolation(
+    parent: UnixDatagram,
+    uid: Uid,
+    gid: Gid,
+    parent_maps: bool,
+    args: &Args,
+) -> Result<ExitStatus> {
+    // Do not outlive the parent, which is killed by SIGINT and SIGTERM until
+    // it has received the TUN file descriptor.
+    prctl::set_pdeathsig(Signal::SIGKILL)?;
+
     // Initialize the mount namespace properly.
     mount::init_namespace()?;
-    mount::procfs(&PathBuf::from("/proc"))?;
+    if args.no_pid_namespace {
+        debug!("keeping the procfs of the host");
+    } else {
+        mount::procfs(&in_rootfs(args, Path::new("/proc")))?;
+    }
+    // Show the network interfaces of the namespace in /sys.
+    if !args.share_sys {
+        let flags =
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+        match mount::sysfs(&in_rootfs(args, Path::new("/sys")), flags) {
+            Ok(()) => {}
+            // There is no /sys of the host within a `--rootfs` to fall back to.
+            Err(mount::MountError::Internal(Errno::EPERM)) if args.rootfs.is_none() => {
+                warn!("not permitted to mount sysfs on /sys, keeping the one of the host");
+            }
+            Err(mount::MountError::Internal(Errno::EPERM)) => {
+                bail!("not permitted to mount sysfs on /sys of the rootfs")
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // Keep temporary files, including the resolv.conf(5) override, private.
+    if !args.share_tmp {
+        mount::tmpfs(&in_rootfs(args, Path::new("/tmp")), None)?;
+    }
+    for (src, dst) in &args.bind {
+        mount::bind(src, &in_rootfs(args, dst))
+            .with_context(|| format!("failed to bind {src:?} to {dst:?}"))?;
+    }
+    for (src, dst) in &args.bind_ro {
+        mount::bind_ro(src, &in_rootfs(args, dst))
+            .with_context(|| format!("failed to bind {src:?} to {dst:?}"))?;
+    }
+    // Switch to the root file system last, as the sources above are host paths.
+    if let Some(rootfs) = &args.rootfs {
+        mount::pivot_root(rootfs, rootfs)
+            .with_context(|| format!("failed to pivot root to {rootfs:?}"))?;
+    }
     debug!("finished mount namespace setup");
 
-    // Perform UID and GID mappings.
-    user::setgroups(false)?;
-    user::uid_map(uid, uid)?;
-    user::gid_map(gid, gid)?;
-    debug!("finished user namespace mappings");
+    // Perform UID and GID mappings, unless the parent maps them.
+    if parent_maps {
+        recv_ready(&parent, "map IDs", READY_TIMEOUT)?;
+        // Unlike the caller's own IDs, foreign IDs have to be switched to.
+        if args.user.is_some() {
+            let (inner_uid, inner_gid) = inner_ids(args, uid, gid);
+            unistd::setresgid(inner_gid, inner_gid, inner_gid)?;
+            unistd::setresuid(inner_uid, inner_uid, inner_uid)?;
+        }
+    } else {
+        user::setgroups(false)?;
+        if args.map_root {
+            let (inner_uid, inner_gid) = inner_ids(args, uid, gid);
+            user::uid_map(&[(inner_uid, uid, 1)])?;
+            user::gid_map(&[(inner_gid, gid, 1)])?;
+        } else {
+            user::uid_map_identity(uid)?;
+            user::gid_map_identity(gid)?;
+        }
+    }
+    debug!("finished user namespace mappings");
+
+    // Replace `/dev` and overlay directories, which needs the mappings for
+    // creating files.
+    if args.fresh_dev {
+        fresh_dev().context("failed to mount a fresh /dev")?;
+    }
+    for dir in &args.overlay {
+        ephemeral(dir).with_context(|| format!("failed to overlay {dir:?}"))?;
+    }
+
+    // Set the hostname in the UTS namespace.
+    if !args.no_uts_namespace {
+        unistd::sethostname(&args.hostname).context("failed to set hostname")?;
+        debug!("set hostname to {}", args.hostname);
+        setdomainname(DEFAULT_DOMAINNAME).context("failed to set NIS domain name")?;
+        debug!("set NIS domain name to {DEFAULT_DOMAINNAME}");
+    }
+
+    // Overwrite `/etc/resolv.conf`, unless the user explicitly asked not to.
+    let net = NetConfig::new(args)?;
+    let _resolv_conf = if args.keep_resolv_conf {
+        debug!("keeping the resolv.conf(5) of the host");
+        None
+    } else {
+        Some(override_resolv_conf(args, &net)?)
+    };
+
+    // Setup the loopback device.
+    let mut socket = netlink::socket()?;
+    let loopback_index = netlink::get_index(&mut socket, LOOPBACK_DEVICE)?;
+    let loopback_addrs = [
+        (IpAddr::V4(Ipv4Addr::LOCALHOST), 8),
+        (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    ];
+    for (addr, prefix_len) in loopback_addrs {
+        if family_enabled(args, &addr) {
+            netlink::ensure_address(&mut socket, loopback_index, addr, prefix_len)?;
+        }
+    }
+    netlink::set_up(&mut socket, loopback_index)?;
+    debug!("finished setting up {LOOPBACK_DEVICE}");
+
+    // Create and configure a TUN interface for use with onionmasq.
+    let tun = tun::open(&args.device_name)?;
+    let tun_index = netlink::get_index(&mut socket, &args.device_name)?;
+    for (addr, prefix_len) in net.tun_addrs() {
+        if family_enabled(args, &addr) {
+            netlink::ensure_address(&mut socket, tun_index, addr, prefix_len)?;
+        }
+    }
+    netlink::set_mtu(&mut socket, tun_index, args.mtu)?;
+    netlink::set_up(&mut socket, tun_index)?;
+    if !args.ipv6_only {
+        netlink::set_default_gateway(&mut socket, tun_index, AddressFamily::Inet)?;
+    }
+    if !args.ipv4_only {
+        netlink::set_default_gateway(&mut socket, tun_index, AddressFamily::Inet6)?;
+    }
+    debug!("finished setting up {}", args.device_name);
+
+    // Close the privileged netlink socket before dropping the capabilities.
+    drop(socket);
+
+    // Drop all capabilities.
+    caps::clear(None, CapSet::Permitted)?;
+    caps::clear(None, CapSet::Effective)?;
+    caps::clear(None, CapSet::Inheritable)?;
+    caps::clear(None, CapSet::Ambient)?;
+    debug!("dropped all capabilites");
+
+    // Restrict the system calls before the parent gets to proceed, so that
+    // neither this process nor the command ever runs unfiltered from here on.
+    if let Some(profile) = &args.seccomp {
+        seccomp::apply(&seccomp::compile(profile)?)?;
+        debug!("applied seccomp filter");
+    }
+
+    // Send the device to the parent.
+    parent.send_with_fd(&[0; 1024], &[tun.as_raw_fd()])?;
+    drop(tun);
+    debug!("sent TUN device");
+
+    // Wait until the parent has created the onion-tunnel on the TUN device.
+    if args.no_wait {
+        debug!("not waiting for onion-tunnel");
+    } else {
+        recv_ready(&parent, "create onion-tunnel", args.ready_timeout)?;
+        debug!("onion-tunnel is ready");
+    }
+
+    if args.self_test {
+        let passed = self_test(args, &net)?;
+        return Ok(ExitStatus::from_raw(i32::from(!passed) << 8));
+    }
+
+    // Check the working directory now that all mounts are in place.
+    if let Some(workdir) = &args.workdir {
+        if !workdir.is_dir() {
+            bail!("working directory {workdir:?} does not exist or is not a directory");
+        }
+    }
+
+    // Run the actual child and wait for its termination.
+    // It is important to not use something like `execve` or anything that else
+    // that could hinder the execution of Rust Drop traits, as otherwise the
+    // `_resolv_conf` file will leak into the temporary directory.
+    let mut child = command(args).spawn().context("failed to spawn command")?;
+    let pid = Pid::from_raw(child.id().try_into()?);
+    let watchdog = args.timeout.map(|timeout| Watchdog::spawn(pid, timeout));
+    let status = if args.no_pid_namespace {
+        relay_signals(pid, false)?;
+        child.wait()?
+    } else {
+        // Act as the init process of the PID namespace.
+        relay_signals(Pid::from_raw(-1), false)?;
+        reap(&mut child)?
+    };
+    // Stop the watchdog before `pid` may be reused by an orphan.
+    let timed_out = watchdog.is_some_and(Watchdog::stop);
+    if !args.no_pid_namespace {
+        terminate_remaining();
+    }
+
+    if timed_out {
+        return Ok(ExitStatus::from_raw(i32::from(EXIT_TIMED_OUT) << 8));
+    }
+    Ok(status)
+}
+
+/// Terminates the command once `--timeout` elapsed, unless stopped before
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Sends `SIGTERM` to `pid` once `timeout` elapsed, followed by `SIGKILL`
+    /// if it is still alive after [`TERMINATION_GRACE`].
+    fn spawn(pid: Pid, timeout: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if stopped.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            // A command which exited on its own did not time out, even if it
+            // has not been waited for yet.
+            let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+            if wait::waitid(Id::Pid(pid), flags) != Ok(WaitStatus::StillAlive) {
+                return false;
+            }
+            warn!(
+                "command timed out after {}, terminating it",
+                humantime::format_duration(timeout)
+            );
+            if signal::kill(pid, Signal::SIGTERM).is_err() {
+                return false;
+            }
+
+            if stopped.recv_timeout(TERMINATION_GRACE) == Err(RecvTimeoutError::Timeout)
+                && signal::kill(pid, Signal::SIGKILL).is_ok()
+            {
+                warn!("command did not terminate in time, killed it");
+            }
+            true
+        });
+
+        Self { stop, thread }
+    }
+
+    /// Stops the watchdog once the command has been waited for and returns
+    /// whether it terminated the command.
+    fn stop(self) -> bool {
+        drop(self.stop);
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+/// Sets the NIS domain name with `setdomainname(2)`, which nix lacks.
+fn setdomainname(name: &str) -> Result<(), Errno> {
+    // SAFETY: The pointer and length describe the valid buffer of `name`.
+    let res = unsafe { libc::setdomainname(name.as_ptr().cast(), name.len()) };
+    Errno::result(res).map(drop)
+}
+
+/// Returns where the absolute `path` of the command ends up before switching to
+/// the `--rootfs`, if any.
+fn in_rootfs(args: &Args, path: &Path) -> PathBuf {
+    match &args.rootfs {
+        Some(rootfs) => rootfs.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Checks from within the isolation that traffic cannot bypass the TUN device
+/// and that DNS queries go to the expected nameservers.
+///
+/// Routing is checked by connecting UDP sockets, which does not send any
+/// packets but lets the kernel choose a route and thus a source address.
+/// Returns whether all checks passed.
+fn self_test(args: &Args, net: &NetConfig) -> Result<bool> {
+    let mut results = Vec::new();
+
+    let mut socket = netlink::socket()?;
+    let foreign: Vec<_> = netlink::list_interfaces(&mut socket)?
+        .into_iter()
+        .map(|(_, name)| name)
+        .filter(|name| name != LOOPBACK_DEVICE && *name != args.device_name)
+        .collect();
+    drop(socket);
+    results.push((
+        "only loopback and TUN interfaces".to_string(),
+        if foreign.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("found {}", foreign.join(", ")))
+        },
+    ));
+
+    let probes = [
+        (IpAddr::V4(SELF_TEST_ADDR_V4), net.tun_addrs()[0].0),
+        (IpAddr::V6(SELF_TEST_ADDR_V6), net.tun_addrs()[1].0),
+    ];
+    for (dst, tun_addr) in probes {
+        if !family_enabled(args, &dst) {
+            continue;
+        }
+        let bind_addr = match dst {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let routed = UdpSocket::bind((bind_addr, 0))
+            .and_then(|udp| udp.connect((dst, 53)).map(|()| udp))
+            .and_then(|udp| udp.local_addr());
+        let result = match routed {
+            Ok(local) if local.ip() == tun_addr => Ok(()),
+            Ok(local) => Err(format!("routed from {}", local.ip())),
+            // Without any route, nothing can leak either.
+            Err(e) if e.raw_os_error() == Some(libc::ENETUNREACH) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+        results.push((
+            format!("{dst} is routed through {}", args.device_name),
+            result,
+        ));
+    }
+
+    let result = if args.keep_resolv_conf {
+        Err("the resolv.conf(5) of the host is kept".to_string())
+    } else {
+        let expected: Vec<_> = if args.dns.is_empty() {
+            net.nameservers()
+                .into_iter()
+                .filter(|ns| family_enabled(args, ns))
+                .collect()
+        } else {
+            args.dns.clone()
+        };
+        fs::read_to_string("/etc/resolv.conf")
+            .map_err(|e| e.to_string())
+            .and_then(|resolv_conf| {
+                let unexpected: Vec<_> = resolv_conf
+                    .lines()
+                    .filter_map(|line| line.trim().strip_prefix("nameserver"))
+                    .map(str::trim)
+                    .filter(|ns| ns.parse().map_or(true, |ns| !expected.contains(&ns)))
+                    .collect();
+                if unexpected.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!("found {}", unexpected.join(", ")))
+                }
+            })
+    };
+    results.push((
+        "/etc/resolv.conf only lists the expected nameservers".to_string(),
+        result,
+    ));
+
+    println!("1..{}", results.len());
+    for (n, (check, result)) in results.iter().enumerate() {
+        match result {
+            Ok(()) => println!("ok {} - {check}", n + 1),
+            Err(e) => println!("not ok {} - {check}: {e}", n + 1),
+        }
+    }
+
+    Ok(results.iter().all(|(_, result)| result.is_ok()))
+}
+
+/// Reaps all terminated processes of the PID namespace, which get reparented
+/// to its init process, until `child` terminates and returns its status.
+fn reap(child: &mut Child) -> Result<ExitStatus> {
+    let pid = Pid::from_raw(child.id().try_into()?);
+    loop {
+        // Only peek, so that `child` itself is waited for by `Child::wait`.
+        match wait::waitid(Id::All, WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT) {
+            Ok(status) if status.pid() == Some(pid) => return Ok(child.wait()?),
+            Ok(status) => {
+                if let Some(orphan) = status.pid() {
+                    wait::waitpid(orphan, None)?;
+                    debug!("reaped orphaned process {orphan}");
+                }
+            }
+            Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Sends SIGTERM to all remaining processes of the PID namespace and reaps
+/// them for at most [`TERMINATION_GRACE`].
+///
+/// Whatever is still running afterwards gets killed by the kernel, once the
+/// init process exits.
+fn terminate_remaining() {
+    if signal::kill(Pid::from_raw(-1), Signal::SIGTERM).is_err() {
+        // There are no processes left.
+        return;
+    }
+    debug!("terminating remaining processes");
+
+    let deadline = Instant::now() + TERMINATION_GRACE;
+    while Instant::now() < deadline {
+        match wait::waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => thread::sleep(Duration::from_millis(50)),
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => return,
+        }
+    }
+    warn!("processes left behind by the command did not terminate in time");
+}
+
+/// Convert `status` into an exit code, following the shell convention of 128
+/// plus the signal number for processes killed by a signal
+fn exit_code(status: ExitStatus) -> i32 {
+    match status.signal() {
+        Some(sig) => {
+            if status.core_dumped() {
+                warn!("command killed by signal {sig} (core dumped)");
+            } else {
+                debug!("command killed by signal {sig}");
+            }
+            128 + sig
+        }
+        None => status.code().unwrap_or(127),
+    }
+}
+
+/// The parent did not report within `timeout` whether it managed to `step`
+#[derive(thiserror::Error, Debug)]
+#[error("parent did not report whether it could {step} within {}", humantime::format_duration(*timeout))]
+struct ReadyTimeout {
+    step: String,
+    timeout: Duration,
+}
+
+/// Waits for the parent to report whether it managed to `step`.
+///
+/// Fails if the parent could not `step` or if no report arrives within
+/// `timeout`.
+fn recv_ready(parent: &UnixDatagram, step: &str, timeout: Duration) -> Result<()> {
+    let mut buf = [0; 1024];
+    parent.set_read_timeout(Some(timeout))?;
+    let n = parent.recv(&mut buf).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ReadyTimeout {
+            step: step.to_string(),
+            timeout,
+        }
+        .into(),
+        _ => anyhow!("parent did not report whether it could {step}: {e}"),
+    })?;
+    parent.set_read_timeout(None)?;
 
-    // Overwrite `/etc/resolv.conf` with a bind mound to use the nameservers
-    // provided by onionmasq.
-    let mut resolv_conf = NamedTempFile::new()?;
-    resolv_conf.write_all("nameserver 169.254.42.53\nnameserver fe80::53\n".as_bytes())?;
-    debug!(
-        "created temporary resolv.conf(5) at {:?}",
-        resolv_conf.path()
-    );
-    mount::bind(resolv_conf.path(), &PathBuf::from("/etc/resolv.conf"))?;
-    debug!("mounted {:?} to /etc/resolv.conf", resolv_conf.path());
+    match &buf[..n] {
+        [MSG_READY] => Ok(()),
+        [MSG_FAILED, msg @ ..] => bail!("failed to {step}: {}", String::from_utf8_lossy(msg)),
+        _ => bail!("received malformed ready message from parent"),
+    }
+}
 
-    // Setup the loopback device.
-    let loopback_index = netlink::get_index(LOOPBACK_DEVICE)?;
-    netlink::add_address(loopback_index, IpAddr::V4(Ipv4Addr::LOCALHOST), 8)?;
-    netlink::add_address(loopback_index, IpAddr::V6(Ipv6Addr::LOCALHOST), 128)?;
-    netlink::set_up(loopback_index)?;
-    debug!("finished setting up {LOOPBACK_DEVICE}");
+/// Reports to the isolation process whether a step, such as creating the
+/// onion-tunnel, succeeded.
+fn send_ready(isolation: &UnixDatagram, ready: Result<(), String>) -> Result<()> {
+    let msg = match ready {
+        Ok(()) => vec![MSG_READY],
+        Err(e) => [&[MSG_FAILED], e.as_bytes()].concat(),
+    };
+    isolation.send(&msg)?;
 
-    // Create and configure a TUN interface for use with onionmasq.
-    let tun = TunTapInterface::new(DEVICE_NAME, Medium::Ip)
-        .context("failed to open tun interface, is tun kmod loaded?")?;
-    let tun_index = netlink::get_index(DEVICE_NAME)?;
-    netlink::add_address(tun_index, IpAddr::V4(Ipv4Addr::new(169, 254, 42, 1)), 24)?;
-    netlink::add_address(
-        tun_index,
-        IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x1)),
-        96,
-    )?;
-    netlink::set_up(tun_index)?;
-    netlink::set_default_gateway(tun_index, AddressFamily::Inet)?;
-    netlink::set_default_gateway(tun_index, AddressFamily::Inet6)?;
-    debug!("finished setting up the TUN device");
+    Ok(())
+}
 
-    // Drop all capabilities.
-    caps::clear(None, CapSet::Permitted)?;
-    caps::clear(None, CapSet::Effective)?;
-    caps::clear(None, CapSet::Inheritable)?;
-    caps::clear(None, CapSet::Ambient)?;
-    debug!("dropped all capabilites");
+/// Runs an onion-tunnel endlessly on the `tun` device.
+///
+/// Once the onion-tunnel has been created, or failed to, this is reported to
+/// the isolation process via `isolation`.
+fn onion_tunnel(
+    tun: OwnedFd,
+    isolation: UnixDatagram,
+    log_connections: bool,
+    exit_country: Option<String>,
+    status: Option<Arc<control::Status>>,
+    shutdown: Arc<Notify>,
+) -> Result<()> {
+    let set_tunnel = |state| {
+        if let Some(status) = &status {
+            status.set_tunnel(state);
+        }
+    };
+    let setup = (|| {
+        let runtime = Runtime::new()?;
+        let cc = exit_country
+            .map(|cc| {
+                cc.parse()
+                    .map_err(|e| anyhow!("unsupported exit country {cc}: {e}"))
+            })
+            .transpose()?;
+        let tunnel = runtime.block_on(async move {
+            let can_mark = LinuxScaffolding::can_mark();
+            let scaffolding = LinuxScaffolding {
+                can_mark,
+                cc,
+                log_connections,
+            };
+            OnionTunnel::create_with_fd(scaffolding, tun, TunnelConfig::default()).await
+        })?;
+        Ok::<_, anyhow::Error>((runtime, tunnel))
+    })();
+    set_tunnel(match setup {
+        Ok(_) => control::TunnelState::Running,
+        Err(_) => control::TunnelState::Stopped,
+    });
+    send_ready(
+        &isolation,
+        setup.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    )?;
+    debug!("reported onion-tunnel readiness");
 
-    // Send the device to the parent.
-    parent.send_with_fd(&[0; 1024], &[tun.as_raw_fd()])?;
-    drop(tun);
-    debug!("sent TUN device");
+    let (runtime, mut tunnel) = setup?;
+    let res = runtime.block_on(async {
+        tokio::select! {
+            res = tunnel.run() => res,
+            () = shutdown.notified() => Ok(()),
+        }
+    });
+    set_tunnel(control::TunnelState::Stopped);
+    res?;
 
-    // The 100ms is a rather arbitrary timeout, but it probably does not hurt
-    // to wait until the parent has received the file descriptor and launched
-    // the onion-tunnel thread.
-    // TODO: Consider using IPC here to indicate that we can continue although
-    // that might be a little bit overkill.
-    thread::sleep(Duration::from_millis(100));
+    // Give the remaining tasks, such as closing streams, a bounded amount of
+    // time to finish.
+    debug!("shutting down onion-tunnel");
+    drop(tunnel);
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
 
-    // Run the actual child and wait for its termination.
-    // It is important to not use something like `execve` or anything that else
-    // that could hinder the execution of Rust Drop traits, as otherwise the
-    // `resolv_conf` file will leak into the temporary directory.
-    let mut child = Command::new(&cmd[0])
-        .args(&cmd[1..])
-        .spawn()
-        .context("failed to spawn command")?;
-    Ok(child.wait()?)
+    Ok(())
 }
 
-/// Runs an onion-tunnel endlessly on the `tun` device.
-fn onion_tunnel(tun: OwnedFd) -> Result<()> {
-    Runtime::new()?.block_on(async move {
-        let can_mark = LinuxScaffolding::can_mark();
-        let scaffolding = LinuxScaffolding {
-            can_mark,
-            cc: None,
-            log_connections: false,
-        };
-        let mut tunnel =
-            OnionTunnel::create_with_fd(scaffolding, tun, TunnelConfig::default()).await?;
-        tunnel.run().await?;
+/// Maps `uid` and `gid` one to one for the isolation process `proc`, as done
+/// for `--user` or if the helpers for subordinate IDs are missing.
+fn user_maps(args: &Args, proc: Pid, uid: Uid, gid: Gid) -> Result<(), user::UserError> {
+    let (inner_uid, inner_gid) = inner_ids(args, uid, gid);
+    user::setgroups_of(proc, false)?;
+    user::uid_map_of(proc, &[(inner_uid, uid, 1)])?;
+    user::gid_map_of(proc, &[(inner_gid, gid, 1)])?;
 
-        Ok(())
-    })
+    Ok(())
 }
 
+/// The UID and GID mappings performed by the parent
+type SubidMaps = (Vec<(Uid, Uid, u32)>, Vec<(Gid, Gid, u32)>);
+
 /// The actual main program.
 fn main_main(args: Args) -> Result<ExitCode> {
-    if !std::path::Path::new("/dev/net/tun").exists() {
-        bail!("tun kernel module not loaded");
+    tun::check_module()?;
+
+    if let Some(ns) = args.dns.iter().find(|ns| !family_enabled(&args, ns)) {
+        bail!("nameserver {ns} belongs to a disabled address family");
     }
+    NetConfig::new(&args)?;
+
+    // Fail early on typos instead of after setting up the namespaces.
+    // The command of a `--rootfs` cannot be looked up from here.
+    if !args.self_test && args.rootfs.is_none() && !command_exists(&args) {
+        error!("command {:?} not found or not executable", args.cmd[0]);
+        return Ok(ExitCode::from(EXIT_COMMAND_NOT_FOUND));
+    }
+
+    // Create the control socket before anything else to fail early.
+    let control_socket = args
+        .control_socket
+        .as_deref()
+        .map(control::ControlSocket::bind)
+        .transpose()?;
 
     // Create IPC primitives.
     let (parent, child) = UnixDatagram::pair()?;
 
     // Obtain user information.
-    let uid = Uid::current();
-    let gid = Gid::current();
+    let (uid, gid) = match args.user {
+        Some((uid, gid)) => (uid, gid.unwrap_or_else(Gid::current)),
+        None => (Uid::current(), Gid::current()),
+    };
+
+    // Subordinate IDs can only be mapped from the outside.
+    let subid_maps = if args.no_subuid || args.user.is_some() {
+        None
+    } else {
+        subid_maps(&args, uid, gid)?
+    };
+    // Foreign IDs given by `--user` can only be mapped from the outside, too.
+    let parent_maps = subid_maps.is_some() || args.user.is_some();
+
+    let mut flags = CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER;
+    if !args.no_pid_namespace {
+        flags |= CloneFlags::CLONE_NEWPID;
+    }
+    if !args.no_uts_namespace {
+        flags |= CloneFlags::CLONE_NEWUTS;
+    }
+    // Together with `CLONE_NEWUSER`, this needs no privileges, and the new IPC
+    // namespace starts out empty, so nothing has to be set up within it.
+    if !args.share_ipc {
+        flags |= CloneFlags::CLONE_NEWIPC;
+    }
 
-    let mut stack = gen_stack();
+    let mut stack = gen_stack()?;
     let proc = unsafe {
         sched::clone(
             Box::new(|| {
@@ -173,63 +1801,1116 @@ fn main_main(args: Args) -> Result<ExitCode> {
                     }
                 };
 
-                match isolation(parent, uid, gid, &args.cmd) {
+                match isolation(parent, uid, gid, parent_maps, &args) {
                     // Use of unwrap is okay because usize >= u32 on our archs.
                     #[allow(clippy::unwrap_used)]
-                    Ok(code) => code.code().unwrap_or(127).try_into().unwrap(),
+                    Ok(status) => exit_code(status).try_into().unwrap(),
                     Err(e) => {
-                        error!("{e}");
-                        1
+                        error!("{e:#}");
+                        failure_code(&e).into()
                     }
                 }
             }),
-            &mut stack,
-            CloneFlags::CLONE_NEWNET
-                | CloneFlags::CLONE_NEWNS
-                | CloneFlags::CLONE_NEWPID
-                | CloneFlags::CLONE_NEWUSER,
+            stack.as_mut_slice(),
+            flags,
             Some(libc::SIGCHLD),
         )
-    }?;
+    }
+    .map_err(|e| match e {
+        Errno::EINVAL => anyhow!(
+            "failed to create namespaces, the kernel may lack support for some of them, \
+             consider --share-ipc or --no-uts-namespace"
+        ),
+        e => anyhow!("failed to create namespaces: {e}"),
+    })?;
     drop(parent);
 
+    // Serve the control socket only now, as threads must not run during
+    // `clone(2)`, which skips the `fork(2)` handlers keeping e.g. the allocator
+    // usable.  Earlier clients wait in the backlog.
+    let status = match &control_socket {
+        Some(socket) => {
+            let status = Arc::new(control::Status::new(proc));
+            control::spawn(socket.listener()?, Arc::clone(&status))?;
+            Some(status)
+        }
+        None => None,
+    };
+
+    if parent_maps {
+        let (mapped, hint) = match &subid_maps {
+            Some((uid_map, gid_map)) => (
+                match user::newuidmap(proc, uid_map) {
+                    // Distributions ship /etc/subuid without the helpers.
+                    Err(user::UserError::HelperSpawn { helper, source })
+                        if source.kind() == io::ErrorKind::NotFound =>
+                    {
+                        warn!("{helper} not found, mapping no subordinate IDs");
+                        user_maps(&args, proc, uid, gid)
+                    }
+                    res => res.and_then(|()| user::newgidmap(proc, gid_map)),
+                },
+                ", consider --no-subuid",
+            ),
+            None => (user_maps(&args, proc, uid, gid), ""),
+        };
+        send_ready(
+            &child,
+            mapped.as_ref().map_err(|e| format!("{e}{hint}")).copied(),
+        )?;
+        // The isolation process reports the failure and exits on its own.
+        if mapped.is_err() {
+            return wait_isolation(proc);
+        }
+    }
+
     // Receive file descriptor.
-    let mut fds = [-1];
-    let (_, nfds) = child.recv_with_fd(&mut [0; 1024], &mut fds)?;
-    assert_eq!(nfds, 1);
-    assert_ne!(fds[0], -1);
-    let tun = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+    let Some(tun) = recv_tun(&child, proc)? else {
+        // The isolation process has already reported why it failed.
+        return wait_isolation(proc);
+    };
     debug!("received TUN file descriptor");
 
+    // Only now there is a command to relay signals to; before, they terminate
+    // us as usual and the isolation process with us.
+    relay_signals(proc, true)?;
+
     // Spawn task to handle the TUN device in.
     // Maybe we could use `Runtime::spawn` instead, but spawning the task
     // ourselves in combinating with `Runtime::block_on` gives me a more fuzzy
     // feeling in terms of control.
-    thread::spawn(|| match onion_tunnel(tun) {
-        Ok(()) => {}
-        Err(e) => error!("{e}"),
+    let isolation = child.try_clone()?;
+    let log_connections = args.log_connections;
+    let exit_country = args.exit_country.clone();
+    let on_tunnel_failure = args.on_tunnel_failure;
+    // Killing the init process of a PID namespace kills everything within it,
+    // otherwise the isolation process relays SIGTERM to the command.
+    let kill_signal = if args.no_pid_namespace {
+        Signal::SIGTERM
+    } else {
+        Signal::SIGKILL
+    };
+    let shutdown = Arc::new(Notify::new());
+    let tunnel_shutdown = Arc::clone(&shutdown);
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let res = onion_tunnel(
+            tun,
+            isolation,
+            log_connections,
+            exit_country,
+            status,
+            tunnel_shutdown,
+        );
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            let _ = done_tx.send(());
+            return;
+        }
+
+        match res {
+            Ok(()) => error!("onion-tunnel terminated unexpectedly"),
+            Err(e) => error!("{e}"),
+        }
+        if let TunnelFailure::Kill = on_tunnel_failure {
+            error!("terminating the command, as its traffic cannot be routed anymore");
+            TUNNEL_FAILED.store(true, Ordering::SeqCst);
+            let _ = signal::kill(proc, kill_signal);
+        }
     });
     debug!("spawned onion-tunnel thread");
 
-    // Wait until the isolation process `proc` has finished and return its
-    // status as an `ExitCode`.
+    let code = wait_isolation(proc)?;
+
+    // Shut the onion-tunnel down gracefully, now that nothing uses it anymore.
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    shutdown.notify_one();
+    if done_rx.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+        warn!("onion-tunnel did not shut down in time");
+    }
+
+    if TUNNEL_FAILED.load(Ordering::SeqCst) {
+        return Ok(ExitCode::from(EXIT_TUNNEL_FAILED));
+    }
+
+    Ok(code)
+}
+
+/// Receives the TUN file descriptor from the isolation process `proc` over
+/// `child`.
+///
+/// Returns `None` if `proc` exited without sending it, which is checked for
+/// periodically, as a datagram socket does not notice its peer going away.
+fn recv_tun(child: &UnixDatagram, proc: Pid) -> Result<Option<OwnedFd>> {
+    let mut fds = [-1];
+    child.set_read_timeout(Some(EXIT_POLL_INTERVAL))?;
+    let nfds = loop {
+        match child.recv_with_fd(&mut [0; 1024], &mut fds) {
+            Ok((_, nfds)) => break nfds,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // Do not reap `proc` yet, so that its status can be waited for.
+                let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+                if wait::waitid(Id::Pid(proc), flags)? != WaitStatus::StillAlive {
+                    return Ok(None);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    };
+    child.set_read_timeout(None)?;
+
+    if nfds != 1 || fds[0] == -1 {
+        bail!("isolation process did not send the TUN file descriptor");
+    }
+
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(fds[0]) }))
+}
+
+/// Wait until the isolation process `proc` has finished and return its status
+/// as an `ExitCode`, using the shell convention of 128 plus the signal number
+/// if it got killed by a signal.
+fn wait_isolation(proc: Pid) -> Result<ExitCode> {
     match wait::waitpid(proc, None)? {
         WaitStatus::Exited(_, code) => Ok(ExitCode::from(u8::try_from(code)?)),
+        WaitStatus::Signaled(_, sig, core_dumped) => {
+            if core_dumped {
+                warn!("isolation process killed by {sig} (core dumped)");
+            } else {
+                debug!("isolation process killed by {sig}");
+            }
+            Ok(ExitCode::from(128 + sig as u8))
+        }
         _ => Ok(ExitCode::FAILURE),
     }
 }
 
+/// Fills in all options that were not given on the command line from the
+/// configuration file.
+fn merge_config(args: &mut Args, matches: &ArgMatches) -> Result<()> {
+    let path = match &args.config {
+        Some(path) => path.clone(),
+        None => match config::default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        },
+    };
+    let config = config::load(&path)?;
+
+    macro_rules! merge {
+        ($($field:ident),*) => {$(
+            if matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine) {
+                if let Some(value) = config.$field {
+                    args.$field = value;
+                }
+            }
+        )*};
+    }
+    merge!(
+        verbose,
+        quiet,
+        log_connections,
+        dns,
+        resolv_search,
+        keep_resolv_conf,
+        map_root,
+        device_name,
+        mtu
+    );
+
+    // Settings which are parsed by clap from strings on the command line.
+    macro_rules! merge_parsed {
+        ($($field:ident: $parse:expr),*) => {$(
+            if matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine) {
+                if let Some(value) = config.$field {
+                    args.$field = ($parse)(value).map_err(|e| {
+                        anyhow!("invalid {} in {path:?}: {e}", stringify!($field).replace('_', "-"))
+                    })?;
+                }
+            }
+        )*};
+    }
+    merge_parsed!(
+        log_format: |format: String| LogFormat::from_str(&format, false),
+        dns4: |addr| Ok::<_, String>(Some(addr)),
+        dns6: |addr| Ok::<_, String>(Some(addr)),
+        tun_ipv4: |cidr: String| parse_tun_ipv4(&cidr).map(Some),
+        tun_ipv6: |cidr: String| parse_tun_ipv6(&cidr).map(Some),
+        exit_country: |cc: String| parse_country_code(&cc).map(Some)
+    );
+
+    // Values from the configuration file have not been validated by clap yet.
+    parse_device_name(&args.device_name)
+        .map_err(|e| anyhow!("invalid device-name in {path:?}: {e}"))?;
+    for domain in &args.resolv_search {
+        parse_search_domain(domain)
+            .map_err(|e| anyhow!("invalid resolv-search in {path:?}: {e}"))?;
+    }
+    if !(netlink::MIN_MTU..=netlink::MAX_MTU).contains(&args.mtu) {
+        bail!(
+            "invalid mtu in {path:?}: must be between {} and {}",
+            netlink::MIN_MTU,
+            netlink::MAX_MTU
+        );
+    }
+    // Neither have the conflicts between options, which clap only checks for
+    // the command line.
+    if args.quiet && args.verbose > 0 {
+        bail!("verbose cannot be combined with quiet");
+    }
+    if !args.dns.is_empty() && (args.dns4.is_some() || args.dns6.is_some()) {
+        bail!("dns cannot be combined with dns4 or dns6");
+    }
+    if args.ipv6_only && (args.dns4.is_some() || args.tun_ipv4.is_some()) {
+        bail!("dns4 and tun-ipv4 cannot be combined with --ipv6-only");
+    }
+    if args.ipv4_only && (args.dns6.is_some() || args.tun_ipv6.is_some()) {
+        bail!("dns6 and tun-ipv6 cannot be combined with --ipv4-only");
+    }
+    if args.keep_resolv_conf
+        && !(args.dns.is_empty()
+            && args.resolv_search.is_empty()
+            && args.dns4.is_none()
+            && args.dns6.is_none())
+    {
+        bail!("keep-resolv-conf cannot be combined with dns, dns4, dns6 or resolv-search");
+    }
+
+    Ok(())
+}
+
+/// Fills the command of `args` from `--cmd-file`, if given.
+fn read_cmd_file(args: &mut Args) -> Result<()> {
+    let Some(path) = &args.cmd_file else {
+        return Ok(());
+    };
+    let content = if path.as_os_str() == "-" {
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .context("failed to read the command from stdin")?;
+        content
+    } else {
+        fs::read_to_string(path)
+            .with_context(|| format!("failed to read the command from {path:?}"))?
+    };
+
+    let sep = if content.contains('\0') { '\0' } else { '\n' };
+    let content = content.strip_suffix(sep).unwrap_or(&content);
+    if content.is_empty() {
+        bail!("{path:?} does not contain a command");
+    }
+    args.cmd = content.split(sep).map(str::to_string).collect();
+
+    Ok(())
+}
+
+/// Configure the logger according to `-v`, `-q` and `--log-format`, with the
+/// level taken from `RUST_LOG` instead if it is set.
+///
+/// The isolation process inherits the logger and thus logs at the same level.
+fn logger(args: &Args) -> env_logger::Builder {
+    let mut builder = if std::env::var_os(env_logger::DEFAULT_FILTER_ENV).is_some() {
+        env_logger::Builder::from_default_env()
+    } else {
+        let level = match (args.quiet, args.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Warn,
+            (false, 1) => LevelFilter::Info,
+            (false, 2) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        };
+        // The verbosity only applies to oniux itself, dependencies stay at warn.
+        let mut builder = env_logger::Builder::new();
+        builder
+            .filter_level(level.min(LevelFilter::Warn))
+            .filter_module(module_path!(), level);
+        // The connections are logged by onion-tunnel at the info level.
+        if args.log_connections {
+            builder.filter_module("onion_tunnel", level.max(LevelFilter::Info));
+        }
+        builder
+    };
+
+    if let LogFormat::Json = args.log_format {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+
+    builder
+}
+
+/// Map `e` to the exit code of oniux, which is [`EXIT_TUN_UNAVAILABLE`] if the
+/// TUN kernel module is missing or inaccessible, [`EXIT_NOT_READY`] if the
+/// parent did not report in time and 1 otherwise
+fn failure_code(e: &anyhow::Error) -> u8 {
+    if e.downcast_ref::<ReadyTimeout>().is_some() {
+        return EXIT_NOT_READY;
+    }
+    match e.downcast_ref::<tun::TunError>() {
+        Some(tun::TunError::ModuleMissing | tun::TunError::PermissionDenied) => {
+            EXIT_TUN_UNAVAILABLE
+        }
+        _ => 1,
+    }
+}
+
 /// Wrapper around [`main_main()`] to properly log errors.
 fn main() -> ExitCode {
     // Necessary steps before invocation of `main_main`.
-    env_logger::init();
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = match Args::from_arg_matches(&matches) {
+        Ok(args) => args,
+        Err(e) => e.exit(),
+    };
+    let merged = merge_config(&mut args, &matches).and_then(|()| read_cmd_file(&mut args));
+    logger(&args).init();
+    if let Err(e) = merged {
+        error!("{e}");
+        return ExitCode::FAILURE;
+    }
 
     match main_main(args) {
         Ok(code) => code,
         Err(e) => {
             error!("{e}");
-            ExitCode::FAILURE
+            ExitCode::from(failure_code(&e))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{ffi::OsStr, fs, path::Path};
+
+    use log::Log;
+    use nix::sys::utsname;
+
+    use super::*;
+
+    /// Parses `args` as given on the command line after `oniux`.
+    fn args(args: &[&str]) -> Args {
+        Args::try_parse_from(["oniux"].iter().chain(args).chain(&["true"])).unwrap()
+    }
+
+    /// Parses `args` like [`args`] and merges the configuration file `config`.
+    fn merged(config: &str, args: &[&str]) -> Result<Args> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+        let path = file.path().to_str().unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(
+                ["oniux", "--config", path]
+                    .iter()
+                    .chain(args)
+                    .chain(&["true"]),
+            )
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        merge_config(&mut args, &matches)?;
+
+        Ok(args)
+    }
+
+    #[test]
+    fn merge_config_prefers_the_command_line() {
+        let args = merged("mtu = 1280\ndevice-name = \"tor0\"", &["--mtu", "9000"]).unwrap();
+        assert_eq!(args.mtu, 9000);
+        assert_eq!(args.device_name, "tor0");
+
+        let args = merged(
+            "log-format = \"json\"\ntun-ipv4 = \"10.0.0.1/8\"\nexit-country = \"de\"",
+            &[],
+        )
+        .unwrap();
+        assert!(matches!(args.log_format, LogFormat::Json));
+        assert_eq!(args.tun_ipv4, Some((Ipv4Addr::new(10, 0, 0, 1), 8)));
+        assert_eq!(args.exit_country.as_deref(), Some("DE"));
+
+        let args = merged("dns6 = \"fe80::54\"", &["--dns6", "fe80::55"]).unwrap();
+        assert_eq!(args.dns6, Some("fe80::55".parse().unwrap()));
+    }
+
+    #[test]
+    fn json_log_format() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let target = env_logger::Target::Pipe(Box::new(file.reopen().unwrap()));
+        let logger = logger(&args(&["--log-format", "json"]))
+            .target(target)
+            .build();
+        logger.log(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("oniux")
+                .args(format_args!("a \"quoted\" message"))
+                .build(),
+        );
+        logger.flush();
+
+        let line: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(file.path()).unwrap()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "oniux");
+        assert_eq!(line["message"], "a \"quoted\" message");
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[test]
+    fn merge_config_validates_values() {
+        assert!(merged("log-format = \"xml\"", &[]).is_err());
+        assert!(merged("tun-ipv6 = \"fe80::1/127\"", &[]).is_err());
+        assert!(merged("exit-country = \"deu\"", &[]).is_err());
+        assert!(merged("mtu = 100", &[]).is_err());
+        assert!(merged("device-name = \"a/b\"", &[]).is_err());
+        assert!(merged("resolv-search = [\"a b\"]", &[]).is_err());
+    }
+
+    #[test]
+    fn merge_config_checks_conflicts() {
+        assert!(merged("verbose = 2", &["--quiet"]).is_err());
+        assert!(merged("quiet = true", &["-v"]).is_err());
+        assert!(merged("dns = [\"169.254.42.53\"]", &["--dns4", "169.254.42.54"]).is_err());
+        assert!(merged("keep-resolv-conf = true", &["--dns6", "fe80::54"]).is_err());
+        assert!(merged("dns4 = \"169.254.42.54\"", &["--ipv6-only"]).is_err());
+        assert!(merged("tun-ipv6 = \"fd00::1/64\"", &["--ipv4-only"]).is_err());
+        assert!(merged("keep-resolv-conf = true", &["--resolv-search", "lan"]).is_err());
+        assert!(merged("quiet = true", &[]).is_ok());
+    }
+
+    #[test]
+    fn failure_code_of_ready_timeout() {
+        let e = anyhow::Error::from(ReadyTimeout {
+            step: "create onion-tunnel".to_string(),
+            timeout: Duration::from_secs(60),
+        });
+        assert_eq!(failure_code(&e), EXIT_NOT_READY);
+        assert_eq!(failure_code(&e.context("while waiting")), EXIT_NOT_READY);
+        assert_eq!(failure_code(&anyhow!("other")), 1);
+    }
+
+    #[test]
+    fn failure_code_of_unavailable_tun() {
+        let e = anyhow::Error::from(tun::TunError::ModuleMissing);
+        assert_eq!(failure_code(&e), EXIT_TUN_UNAVAILABLE);
+        assert_eq!(
+            failure_code(&e.context("while opening")),
+            EXIT_TUN_UNAVAILABLE
+        );
+        let e = anyhow::Error::from(tun::TunError::PermissionDenied);
+        assert_eq!(failure_code(&e), EXIT_TUN_UNAVAILABLE);
+    }
+
+    #[test]
+    fn parse_device_name_rejects_invalid_names() {
+        assert_eq!(parse_device_name("tor0"), Ok("tor0".to_string()));
+        assert!(parse_device_name("").is_err());
+        assert!(parse_device_name("a-very-long-name").is_err());
+        assert!(parse_device_name("tun/0").is_err());
+        assert!(parse_device_name("..").is_err());
+        assert!(parse_device_name("tun:0").is_err());
+        assert!(parse_device_name("tun 0").is_err());
+    }
+
+    #[test]
+    fn parse_search_domain_rejects_whitespace() {
+        assert_eq!(parse_search_domain("lan"), Ok("lan".to_string()));
+        assert!(parse_search_domain("").is_err());
+        assert!(parse_search_domain("a lan").is_err());
+    }
+
+    #[test]
+    fn parse_hostname_checks_the_length() {
+        assert_eq!(parse_hostname("oniux"), Ok("oniux".to_string()));
+        assert!(parse_hostname("").is_err());
+        assert!(parse_hostname(&"a".repeat(HOST_NAME_MAX + 1)).is_err());
+    }
+
+    #[test]
+    fn parse_overlay_requires_an_absolute_directory() {
+        assert_eq!(parse_overlay("/tmp"), Ok(PathBuf::from("/tmp")));
+        assert!(parse_overlay("tmp").is_err());
+        assert!(parse_overlay("/nonexistent").is_err());
+        assert!(parse_overlay("/tmp,upperdir=/").is_err());
+    }
+
+    #[test]
+    fn rootfs_paths_are_joined() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().to_str().unwrap();
+        assert_eq!(
+            in_rootfs(&args(&[]), Path::new("/proc")),
+            Path::new("/proc")
+        );
+        assert_eq!(
+            in_rootfs(&args(&["--rootfs", rootfs]), Path::new("/proc")),
+            dir.path().join("proc")
+        );
+        assert!(parse_rootfs(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_country_code_normalizes() {
+        assert_eq!(parse_country_code("de"), Ok("DE".to_string()));
+        assert!(parse_country_code("deu").is_err());
+        assert!(parse_country_code("d1").is_err());
+    }
+
+    #[test]
+    fn parse_seccomp_accepts_profiles_and_files() {
+        assert!(matches!(
+            parse_seccomp("deny-dangerous"),
+            Ok(seccomp::Profile::DenyDangerous)
+        ));
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(matches!(
+            parse_seccomp(file.path().to_str().unwrap()),
+            Ok(seccomp::Profile::File(path)) if path == file.path()
+        ));
+        assert!(parse_seccomp("strict").is_err());
+    }
+
+    #[test]
+    fn parse_user_with_optional_gid() {
+        assert_eq!(parse_user("1000"), Ok((Uid::from_raw(1000), None)));
+        assert_eq!(
+            parse_user("1000:100"),
+            Ok((Uid::from_raw(1000), Some(Gid::from_raw(100))))
+        );
+        assert!(parse_user("alice").is_err());
+        assert!(parse_user("1000:").is_err());
+    }
+
+    #[test]
+    fn parse_bind_requires_absolute_paths() {
+        assert_eq!(
+            parse_bind("/tmp:/mnt"),
+            Ok((PathBuf::from("/tmp"), PathBuf::from("/mnt")))
+        );
+        assert!(parse_bind("/tmp").is_err());
+        assert!(parse_bind("tmp:/mnt").is_err());
+        assert!(parse_bind("/nonexistent:/mnt").is_err());
+    }
+
+    #[test]
+    fn parse_env_var_splits_at_the_first_equals_sign() {
+        assert_eq!(
+            parse_env_var("A=b=c"),
+            Ok(("A".to_string(), "b=c".to_string()))
+        );
+        assert_eq!(parse_env_var("A="), Ok(("A".to_string(), String::new())));
+        assert!(parse_env_var("=b").is_err());
+        assert!(parse_env_var("A").is_err());
+    }
+
+    #[test]
+    fn command_sets_after_unsetting() {
+        let args = args(&["--unsetenv", "A", "--setenv", "A=1", "--unsetenv", "B"]);
+        let cmd = command(&args);
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("A"), Some(OsStr::new("1")))));
+        assert!(envs.contains(&(OsStr::new("B"), None)));
+    }
+
+    #[test]
+    fn command_keeps_variables_despite_clearenv() {
+        let (key, value) = env::vars_os()
+            .find(|(key, _)| KEPT_ENV_VARS.iter().all(|kept| key != kept))
+            .unwrap();
+        let key = key.to_str().unwrap();
+        let cleared = command(&args(&["--clearenv"]));
+        assert!(cleared.get_envs().all(|(k, _)| k != key));
+        let kept = command(&args(&["--env-clear", "--env-keep", key]));
+        assert!(kept
+            .get_envs()
+            .any(|env| env == (OsStr::new(key), Some(&value))));
+        assert!(Args::try_parse_from(["oniux", "--keepenv", key, "true"]).is_err());
+    }
+
+    #[test]
+    fn environment_aliases() {
+        let args = args(&["--clear-env", "--unset", "A", "--env", "B=1"]);
+        assert!(args.clearenv);
+        assert_eq!(args.unsetenv, ["A"]);
+        assert_eq!(args.setenv, [("B".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn keep_resolv_conf_alias() {
+        assert!(args(&["--no-resolv-conf"]).keep_resolv_conf);
+        assert!(
+            Args::try_parse_from(["oniux", "--no-resolv-conf", "--dns", "1.1.1.1", "true"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn command_runs_in_the_workdir() {
+        assert_eq!(command(&args(&[])).get_current_dir(), None);
+        let args = args(&["--workdir", "/tmp"]);
+        assert_eq!(command(&args).get_current_dir(), Some(Path::new("/tmp")));
+    }
+
+    #[test]
+    fn command_exists_looks_up_the_path() {
+        let exists = |args: &[&str]| {
+            command_exists(&Args::try_parse_from(["oniux"].iter().chain(args)).unwrap())
+        };
+        assert!(exists(&["sh"]));
+        assert!(!exists(&["oniux-does-not-exist"]));
+        assert!(exists(&["/bin/sh"]));
+        assert!(!exists(&["/nonexistent/sh"]));
+        assert!(!exists(&["--setenv", "PATH=/nonexistent", "sh"]));
+        assert!(exists(&["--unsetenv", "PATH", "sh"]));
+        assert!(exists(&["--workdir", "/nonexistent", "./sh"]));
+    }
+
+    #[test]
+    fn parse_timeout_rejects_zero() {
+        assert_eq!(parse_timeout("90s"), Ok(Duration::from_secs(90)));
+        assert!(parse_timeout("0s").is_err());
+        assert!(parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn net_config_defaults() {
+        let net = NetConfig::new(&args(&[])).unwrap();
+        assert_eq!(net.tun_v4, TUN_ADDR_V4);
+        assert_eq!(net.tun_v6, TUN_ADDR_V6);
+        assert_eq!(net.dns_v4, Ipv4Addr::new(169, 254, 42, 53));
+        assert_eq!(net.dns_v6, "fe80::53".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn net_config_derives_host_53() {
+        let net = NetConfig::new(&args(&[
+            "--tun-ipv4",
+            "10.9.0.1/16",
+            "--tun-ipv6",
+            "fd00::1/64",
+        ]))
+        .unwrap();
+        assert_eq!(net.dns_v4, Ipv4Addr::new(10, 9, 0, 53));
+        assert_eq!(net.dns_v6, "fd00::53".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn net_config_derives_last_hosts_of_small_subnets() {
+        let net = NetConfig::new(&args(&[
+            "--tun-ipv4",
+            "10.0.0.1/28",
+            "--tun-ipv6",
+            "fd00::1/125",
+        ]))
+        .unwrap();
+        assert_eq!(net.dns_v4, Ipv4Addr::new(10, 0, 0, 14));
+        assert_eq!(net.dns_v6, "fd00::6".parse::<Ipv6Addr>().unwrap());
+
+        let net = NetConfig::new(&args(&["--tun-ipv4", "10.0.0.2/30"])).unwrap();
+        assert_eq!(net.dns_v4, Ipv4Addr::new(10, 0, 0, 1));
+    }
+
+    #[test]
+    fn net_config_avoids_the_tun_address() {
+        let net = NetConfig::new(&args(&["--tun-ipv4", "10.0.0.53/24"])).unwrap();
+        assert_eq!(net.dns_v4, Ipv4Addr::new(10, 0, 0, 254));
+    }
+
+    #[test]
+    fn net_config_checks_explicit_nameservers() {
+        let net = NetConfig::new(&args(&["--tun-ipv4", "10.0.0.1/28", "--dns4", "10.0.0.5"]));
+        assert_eq!(net.unwrap().dns_v4, Ipv4Addr::new(10, 0, 0, 5));
+
+        assert!(
+            NetConfig::new(&args(&["--tun-ipv4", "10.0.0.1/28", "--dns4", "10.0.0.53"])).is_err()
+        );
+        assert!(
+            NetConfig::new(&args(&["--tun-ipv4", "10.0.0.1/24", "--dns4", "10.0.0.1"])).is_err()
+        );
+        assert!(NetConfig::new(&args(&["--dns6", "fd00::53"])).is_err());
+    }
+
+    #[test]
+    fn parse_cidr_checks_the_prefix_length() {
+        assert_eq!(
+            parse_tun_ipv4("10.0.0.1/8"),
+            Ok((Ipv4Addr::new(10, 0, 0, 1), 8))
+        );
+        assert_eq!(
+            parse_tun_ipv6("fd00::1/126"),
+            Ok((Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1), 126))
+        );
+        assert!(parse_tun_ipv4("10.0.0.1/31").is_err());
+        assert!(parse_tun_ipv4("10.0.0.1/0").is_err());
+        assert!(parse_tun_ipv4("10.0.0.1").is_err());
+        assert!(parse_tun_ipv4("fd00::1/64").is_err());
+        assert!(parse_tun_ipv6("fd00::1/127").is_err());
+    }
+
+    #[test]
+    fn check_nameserver_requires_the_subnet() {
+        let tun = IpAddr::V4(Ipv4Addr::new(169, 254, 42, 1));
+        assert!(check_nameserver(IpAddr::V4(Ipv4Addr::new(169, 254, 42, 53)), tun, 24).is_ok());
+        assert!(check_nameserver(IpAddr::V4(Ipv4Addr::new(169, 254, 43, 53)), tun, 24).is_err());
+        assert!(check_nameserver(tun, tun, 24).is_err());
+
+        let tun = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let dns = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x53));
+        assert!(check_nameserver(dns, tun, 96).is_ok());
+        assert!(check_nameserver(dns, tun, 127).is_err());
+    }
+
+    #[test]
+    fn gen_resolv_conf_lists_nameservers_and_search() {
+        let nameservers = [
+            IpAddr::V4(Ipv4Addr::new(169, 254, 42, 53)),
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x53)),
+        ];
+        assert_eq!(
+            gen_resolv_conf(&nameservers, &[]),
+            "nameserver 169.254.42.53\nnameserver fe80::53\n"
+        );
+        assert_eq!(
+            gen_resolv_conf(&nameservers[..1], &["lan".to_string(), "home".to_string()]),
+            "nameserver 169.254.42.53\nsearch lan home\n"
+        );
+    }
+
+    #[test]
+    fn forward_signal_skips_uncatchable_signals() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let proc = Pid::from_raw(child.id().try_into().unwrap());
+        forward_signal(proc, Signal::SIGKILL);
+        forward_signal(proc, Signal::SIGSTOP);
+        thread::sleep(Duration::from_millis(50));
+        assert!(child.try_wait().unwrap().is_none());
+
+        forward_signal(proc, Signal::SIGHUP);
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGHUP));
+    }
+
+    #[test]
+    fn recv_tun_notices_early_exit() {
+        let (_parent, child) = UnixDatagram::pair().unwrap();
+        // SAFETY: The child exits right away without sending anything.
+        let proc = match unsafe { nix::unistd::fork() }.unwrap() {
+            nix::unistd::ForkResult::Child => unsafe { libc::_exit(3) },
+            nix::unistd::ForkResult::Parent { child } => child,
+        };
+        assert!(recv_tun(&child, proc).unwrap().is_none());
+        assert_eq!(wait::waitpid(proc, None), Ok(WaitStatus::Exited(proc, 3)));
+    }
+
+    #[test]
+    fn exit_code_uses_128_plus_signal() {
+        let status = |script| Command::new("sh").args(["-c", script]).status().unwrap();
+        assert_eq!(exit_code(status("exit 3")), 3);
+        assert_eq!(exit_code(status("kill -TERM $$")), 143);
+    }
+
+    #[test]
+    fn wait_isolation_uses_128_plus_signal() {
+        // SAFETY: The child terminates itself right away.
+        let proc = match unsafe { nix::unistd::fork() }.unwrap() {
+            nix::unistd::ForkResult::Child => unsafe {
+                libc::raise(libc::SIGTERM);
+                libc::_exit(0)
+            },
+            nix::unistd::ForkResult::Parent { child } => child,
+        };
+        assert_eq!(wait_isolation(proc).unwrap(), ExitCode::from(143));
+    }
+
+    #[test]
+    fn stack_overflow_hits_the_guard_page() {
+        let mut stack = Stack::new(MIN_STACK_SIZE + 1).unwrap();
+        let usable = stack.as_mut_slice();
+        assert!(usable.len() > MIN_STACK_SIZE);
+        usable.fill(1);
+
+        // SAFETY: The child only writes just below the usable part and exits.
+        let proc = match unsafe { nix::unistd::fork() }.unwrap() {
+            nix::unistd::ForkResult::Child => unsafe {
+                stack.as_mut_slice().as_mut_ptr().sub(1).write_volatile(0);
+                libc::_exit(0)
+            },
+            nix::unistd::ForkResult::Parent { child } => child,
+        };
+        assert!(matches!(
+            wait::waitpid(proc, None),
+            Ok(WaitStatus::Signaled(_, Signal::SIGSEGV, _))
+        ));
+    }
+
+    #[test]
+    fn ready_messages_round_trip() {
+        let (parent, child) = UnixDatagram::pair().unwrap();
+        send_ready(&child, Ok(())).unwrap();
+        assert!(recv_ready(&parent, "create onion-tunnel", READY_TIMEOUT).is_ok());
+
+        send_ready(&child, Err("no route".to_string())).unwrap();
+        let e = recv_ready(&parent, "create onion-tunnel", READY_TIMEOUT).unwrap_err();
+        assert_eq!(e.to_string(), "failed to create onion-tunnel: no route");
+    }
+
+    #[test]
+    fn recv_ready_times_out() {
+        let (parent, _child) = UnixDatagram::pair().unwrap();
+        let e = recv_ready(&parent, "create onion-tunnel", Duration::from_millis(10)).unwrap_err();
+        assert!(e.is::<ReadyTimeout>());
+    }
+
+    #[test]
+    fn family_options() {
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let both = args(&[]);
+        assert!(family_enabled(&both, &v4) && family_enabled(&both, &v6));
+        let ipv4_only = args(&["--ipv4-only"]);
+        assert!(family_enabled(&ipv4_only, &v4) && !family_enabled(&ipv4_only, &v6));
+        let ipv6_only = args(&["--ipv6-only"]);
+        assert!(!family_enabled(&ipv6_only, &v4) && family_enabled(&ipv6_only, &v6));
+        assert!(Args::try_parse_from(["oniux", "--ipv4-only", "--ipv6-only", "true"]).is_err());
+    }
+
+    #[test]
+    fn on_tunnel_failure_options() {
+        assert!(matches!(args(&[]).on_tunnel_failure, TunnelFailure::Kill));
+        let args = args(&["--on-tunnel-failure", "ignore"]);
+        assert!(matches!(args.on_tunnel_failure, TunnelFailure::Ignore));
+    }
+
+    #[test]
+    fn command_dies_with_isolation_without_pid_namespace() {
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        // SAFETY: The child only spawns the command and exits right away.
+        let isolation = match unsafe { unistd::fork() }.unwrap() {
+            unistd::ForkResult::Child => {
+                let args = Args::try_parse_from(["oniux", "--no-pid-namespace", "sleep", "10"]);
+                let pid = args
+                    .ok()
+                    .and_then(|args| command(&args).spawn().ok())
+                    .map_or(0, |child| child.id());
+                let _ = sender.send(&pid.to_ne_bytes());
+                // SAFETY: Exiting without running the handlers of the parent.
+                unsafe { libc::_exit(0) }
+            }
+            unistd::ForkResult::Parent { child } => child,
+        };
+        let mut pid = [0; 4];
+        receiver.recv(&mut pid).unwrap();
+        wait::waitpid(isolation, None).unwrap();
+        let pid = u32::from_ne_bytes(pid);
+        assert_ne!(pid, 0);
+
+        // The orphaned command is reaped by the init process of the host.
+        let stat = format!("/proc/{pid}/stat");
+        let dead = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(20));
+            fs::read_to_string(&stat).map_or(true, |stat| stat.contains(") Z "))
+        });
+        assert!(dead, "command {pid} survived the isolation process");
+    }
+
+    #[test]
+    fn device_name_options() {
+        assert_eq!(args(&[]).device_name, DEVICE_NAME);
+        for option in ["-n", "--name", "--tun-name", "--device-name"] {
+            assert_eq!(args(&[option, "tor1"]).device_name, "tor1");
+        }
+        assert!(Args::try_parse_from(["oniux", "-n", "a/b", "true"]).is_err());
+    }
+
+    #[test]
+    fn share_ipc_options() {
+        assert!(!args(&[]).share_ipc);
+        assert!(args(&["--share-ipc"]).share_ipc);
+        assert!(args(&["--no-ipc-ns"]).share_ipc);
+    }
+
+    #[test]
+    fn self_test_replaces_the_command() {
+        assert!(Args::try_parse_from(["oniux", "--self-test"]).is_ok_and(|args| args.self_test));
+        assert!(Args::try_parse_from(["oniux"]).is_err());
+        assert!(Args::try_parse_from(["oniux", "--self-test", "true"]).is_err());
+    }
+
+    #[test]
+    fn ipc_namespace_hides_host_objects() {
+        // SAFETY: The segment is only created and removed again.
+        let id = unsafe { libc::shmget(libc::IPC_PRIVATE, 4096, libc::IPC_CREAT | 0o600) };
+        assert!(id >= 0, "{}", Errno::last());
+
+        testutil::in_namespaces(CloneFlags::CLONE_NEWIPC, || {
+            // SAFETY: Only the existence of the segment is queried.
+            let res = unsafe { libc::shmctl(id, libc::IPC_STAT, &mut std::mem::zeroed()) };
+            res == -1 && Errno::last() == Errno::EINVAL
+        });
+        // SAFETY: The segment has been created above.
+        unsafe { libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn workdir_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(dir.path()).unwrap();
+        for option in ["--workdir", "--chdir"] {
+            let args = Args::try_parse_from([
+                "oniux".as_ref(),
+                option.as_ref(),
+                dir.as_os_str(),
+                "pwd".as_ref(),
+            ])
+            .unwrap();
+            assert_eq!(args.workdir.as_deref(), Some(dir.as_path()));
+
+            let output = command(&args).output().unwrap();
+            assert_eq!(
+                output.stdout,
+                [dir.as_os_str().as_encoded_bytes(), b"\n"].concat()
+            );
         }
     }
+
+    #[test]
+    fn uts_namespace_names() {
+        let host = utsname::uname().unwrap();
+        testutil::in_namespaces(CloneFlags::CLONE_NEWUTS, || {
+            let set = unistd::sethostname("oniux-test").is_ok()
+                && setdomainname(DEFAULT_DOMAINNAME).is_ok()
+                && setdomainname(&"a".repeat(65)) == Err(Errno::EINVAL);
+            set && utsname::uname().is_ok_and(|names| {
+                names.nodename() == "oniux-test" && names.domainname() == DEFAULT_DOMAINNAME
+            })
+        });
+        assert_eq!(utsname::uname().unwrap().nodename(), host.nodename());
+    }
+
+    #[test]
+    fn ephemeral_discards_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        fs::write(path.join("kept"), "host").unwrap();
+
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            mount::init_namespace().is_ok()
+                && ephemeral(path).is_ok()
+                && fs::read(path.join("kept")).is_ok_and(|content| content == b"host")
+                && fs::write(path.join("kept"), "changed").is_ok()
+                && fs::write(path.join("added"), "").is_ok()
+        });
+        assert_eq!(fs::read(path.join("kept")).unwrap(), b"host");
+        assert!(!path.join("added").exists());
+    }
+
+    #[test]
+    fn fresh_dev_only_has_the_minimal_devices() {
+        let names: Vec<_> = DEV_NODES
+            .iter()
+            .map(|(name, _, _)| *name)
+            .chain(["fd", "stdin", "stdout", "stderr"])
+            .collect();
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            let minimal = |entries: fs::ReadDir| {
+                entries.flatten().all(|entry| {
+                    entry
+                        .file_name()
+                        .to_str()
+                        .is_some_and(|name| names.contains(&name))
+                })
+            };
+            mount::init_namespace().is_ok()
+                && fresh_dev().is_ok()
+                && fs::read_dir("/dev").is_ok_and(minimal)
+                && fs::write("/dev/null", "discarded").is_ok()
+                && fs::read("/dev/null").is_ok_and(|content| content.is_empty())
+        });
+    }
+
+    #[test]
+    fn reap_orphans_as_init() {
+        testutil::in_namespaces(CloneFlags::CLONE_NEWPID, || {
+            // Only the first child becomes the init process of the namespace.
+            // SAFETY: The child only runs the command and exits right away.
+            let Ok(unistd::ForkResult::Parent { child: init }) = (unsafe { unistd::fork() }) else {
+                // The command daemonizes a process and exits.
+                let start = Instant::now();
+                let passed = Command::new("sh")
+                    .args(["-c", "sleep 10 & exit 7"])
+                    .spawn()
+                    .is_ok_and(|mut child| {
+                        reap(&mut child).is_ok_and(|status| status.code() == Some(7))
+                    });
+                terminate_remaining();
+                // The orphaned sleep received SIGTERM and nothing is left to reap.
+                let passed = passed
+                    && wait::waitpid(Pid::from_raw(-1), None) == Err(Errno::ECHILD)
+                    && start.elapsed() < Duration::from_secs(5);
+                // SAFETY: Exiting without running the handlers of the parent.
+                unsafe { libc::_exit(i32::from(!passed)) }
+            };
+            wait::waitpid(init, None).is_ok_and(|status| status == WaitStatus::Exited(init, 0))
+        });
+    }
+
+    /// Reads the command of `content` with `--cmd-file`.
+    fn cmd_from_file(content: &str) -> Result<Vec<String>> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut args = Args::try_parse_from(["oniux", "--cmd-file", path]).unwrap();
+        read_cmd_file(&mut args)?;
+
+        Ok(args.cmd)
+    }
+
+    #[test]
+    fn read_cmd_file_splits_arguments() {
+        assert_eq!(
+            cmd_from_file("echo\nhello world\n").unwrap(),
+            ["echo", "hello world"]
+        );
+        assert_eq!(cmd_from_file("printf\0a\nb\0").unwrap(), ["printf", "a\nb"]);
+        assert_eq!(cmd_from_file("echo\n\n").unwrap(), ["echo", ""]);
+        assert!(cmd_from_file("").is_err());
+        assert!(cmd_from_file("\n").is_err());
+    }
+
+    #[test]
+    fn watchdog_terminates_the_command() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = Pid::from_raw(child.id().try_into().unwrap());
+        let watchdog = Watchdog::spawn(pid, Duration::from_millis(50));
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+        assert!(watchdog.stop());
+    }
+
+    #[test]
+    fn watchdog_ignores_exited_commands() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = Pid::from_raw(child.id().try_into().unwrap());
+        let watchdog = Watchdog::spawn(pid, Duration::from_secs(60));
+        assert!(child.wait().unwrap().success());
+        assert!(!watchdog.stop());
+
+        // The command exited right before the timeout, but was not waited for.
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = Pid::from_raw(child.id().try_into().unwrap());
+        thread::sleep(Duration::from_millis(100));
+        let watchdog = Watchdog::spawn(pid, Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(100));
+        assert!(child.wait().unwrap().success());
+        assert!(!watchdog.stop());
+    }
 }
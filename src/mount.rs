@@ -1,17 +1,39 @@
 //! Implements functionality around `mount(2)` system calls
 
-use std::path::Path;
+use std::{
+    ffi::OsString,
+    fs, io,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
 
 use log::debug;
-use nix::mount::{self, MsFlags};
+use nix::{
+    errno::Errno,
+    mount::{self, MsFlags},
+    sys::{
+        statvfs::{self, FsFlags},
+        utsname,
+    },
+    unistd,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum MountError {
     #[error("failed to mount: {0}")]
     Internal(#[from] nix::errno::Errno),
+    #[error("{path:?} is not an existing directory")]
+    MissingDirectory { path: PathBuf },
+    #[error("failed to list the mounts beneath {path:?}: {source}")]
+    MountInfo { path: PathBuf, source: io::Error },
+    #[error("overlayfs within user namespaces requires Linux {}.{} or newer, running {release}", OVERLAY_MIN_VERSION.0, OVERLAY_MIN_VERSION.1)]
+    OverlayUnsupported { release: String },
 }
 
+/// The first Linux version allowing overlayfs mounts in user namespaces
+const OVERLAY_MIN_VERSION: (u32, u32) = (5, 11);
+
 /// Initialize a freshly created mount namespace.
 ///
 /// This function first mounts `/` with [`MsFlags::MS_REC`] and
@@ -41,10 +63,362 @@ pub fn procfs(path: &Path) -> Result<(), MountError> {
     Ok(())
 }
 
+/// Mounts `sysfs` at `path` with `flags`, e.g. [`MsFlags::MS_RDONLY`].
+///
+/// The network related parts of `sysfs` reflect the network namespace of the
+/// calling process.
+pub fn sysfs(path: &Path, flags: MsFlags) -> Result<(), MountError> {
+    mount::mount(Some("sysfs"), path, Some("sysfs"), flags, Some(""))?;
+    debug!("mounted `sysfs` at `{:?}`", path);
+
+    Ok(())
+}
+
 /// Creates a [`MsFlags::MS_BIND`] mount between `src` and `dst`.
+///
+/// The bind mount is recursive, as mounts beneath `src` may be locked within
+/// a `user_namespaces(7)`, which cannot be left out.
 pub fn bind(src: &Path, dst: &Path) -> Result<(), MountError> {
-    mount::mount(Some(src), dst, Some(""), MsFlags::MS_BIND, Some(""))?;
+    mount::mount(
+        Some(src),
+        dst,
+        Some(""),
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        Some(""),
+    )?;
     debug!("created bind mount {:?} -> {:?}", src, dst);
 
     Ok(())
 }
+
+/// Mounts a `tmpfs` at `path`, limited to `size_bytes` if given.
+pub fn tmpfs(path: &Path, size_bytes: Option<u64>) -> Result<(), MountError> {
+    let data = match size_bytes {
+        Some(size) => format!("size={size}"),
+        None => String::new(),
+    };
+    mount::mount(
+        Some("tmpfs"),
+        path,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(data.as_str()),
+    )?;
+    debug!("mounted `tmpfs` at `{:?}`", path);
+
+    Ok(())
+}
+
+/// Mounts a fresh `devtmpfs` at `path`.
+///
+/// Mounting `devtmpfs` requires `CAP_SYS_ADMIN` in the initial user namespace,
+/// hence an empty `tmpfs` is mounted instead if that is not permitted.
+pub fn devtmpfs(path: &Path) -> Result<(), MountError> {
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC;
+    match mount::mount(
+        Some("devtmpfs"),
+        path,
+        Some("devtmpfs"),
+        flags,
+        Some("mode=755"),
+    ) {
+        Ok(()) => debug!("mounted `devtmpfs` at `{:?}`", path),
+        Err(Errno::EPERM) => {
+            mount::mount(Some("tmpfs"), path, Some("tmpfs"), flags, Some("mode=755"))?;
+            debug!("mounted `tmpfs` in place of `devtmpfs` at `{:?}`", path);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+/// Mounts an overlayfs of `upper` over `lower` at `merged`, using `work` as
+/// its work directory.
+///
+/// `upper` and `work` have to be existing directories on the same file
+/// system. `lower` and `merged` may be the same, hiding `lower` beneath it.
+pub fn overlay(lower: &Path, upper: &Path, work: &Path, merged: &Path) -> Result<(), MountError> {
+    let uname = utsname::uname()?;
+    let release = uname.release().to_string_lossy();
+    let mut version = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().unwrap_or(0));
+    let version = (version.next().unwrap_or(0), version.next().unwrap_or(0));
+    if version < OVERLAY_MIN_VERSION {
+        return Err(MountError::OverlayUnsupported {
+            release: release.into_owned(),
+        });
+    }
+    for dir in [upper, work] {
+        if !dir.is_dir() {
+            return Err(MountError::MissingDirectory {
+                path: dir.to_path_buf(),
+            });
+        }
+    }
+
+    let data = format!(
+        "lowerdir={},upperdir={},workdir={},userxattr",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    mount::mount(
+        Some("overlay"),
+        merged,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(data.as_str()),
+    )?;
+    debug!(
+        "mounted `overlay` of {:?} over {:?} at {:?}",
+        upper, lower, merged
+    );
+
+    Ok(())
+}
+
+/// Detaches the mount at `path`, see `MNT_DETACH` in `umount(2)`.
+pub fn detach(path: &Path) -> Result<(), MountError> {
+    mount::umount2(path, mount::MntFlags::MNT_DETACH)?;
+    debug!("detached {:?}", path);
+
+    Ok(())
+}
+
+/// Makes `new_root` the root file system with `pivot_root(2)` and detaches
+/// the old root, which is temporarily put at `put_old`.
+///
+/// `put_old` has to be `new_root` itself or a directory beneath it. Binding
+/// `new_root` onto itself beforehand ensures that it is a mount point, as
+/// required by the kernel. Afterwards, the working directory is `/`.
+pub fn pivot_root(new_root: &Path, put_old: &Path) -> Result<(), MountError> {
+    mount::mount(
+        Some(new_root),
+        new_root,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    unistd::pivot_root(new_root, put_old)?;
+    debug!("pivoted root to {:?}", new_root);
+
+    // The old root is stacked on top of `/` if `put_old` is `new_root`.
+    let old_root = Path::new("/").join(put_old.strip_prefix(new_root).unwrap_or(put_old));
+    unistd::chdir(&old_root)?;
+    mount::umount2(&old_root, mount::MntFlags::MNT_DETACH)?;
+    unistd::chdir("/")?;
+    debug!("detached old root at {:?}", old_root);
+
+    Ok(())
+}
+
+/// Moves the mount at `src` to `dst` with [`MsFlags::MS_MOVE`].
+pub fn move_mount(src: &Path, dst: &Path) -> Result<(), MountError> {
+    mount::mount(Some(src), dst, None::<&str>, MsFlags::MS_MOVE, None::<&str>)?;
+    debug!("moved mount {:?} -> {:?}", src, dst);
+
+    Ok(())
+}
+
+/// Changes the flags of the existing mount at `path` to `flags`.
+///
+/// [`MsFlags::MS_REMOUNT`] is added implicitly. Within a
+/// `user_namespaces(7)`, the remount must keep the locked flags of the
+/// original mount, such as `MS_NOSUID`, hence these are carried over.
+pub fn remount(path: &Path, flags: MsFlags) -> Result<(), MountError> {
+    let locked = statvfs::statvfs(path)?.flags();
+    let mut flags = flags | MsFlags::MS_REMOUNT;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if locked.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount::mount(None::<&str>, path, None::<&str>, flags, None::<&str>)?;
+    debug!("remounted {:?} with {:?}", path, flags);
+
+    Ok(())
+}
+
+/// Creates a read-only [`MsFlags::MS_BIND`] mount between `src` and `dst`.
+///
+/// `MS_RDONLY` is ignored when creating a bind mount, hence it is remounted
+/// read-only afterwards.  As [`bind`] is recursive, this includes all mounts
+/// beneath `dst`, which would stay writable otherwise.
+pub fn bind_ro(src: &Path, dst: &Path) -> Result<(), MountError> {
+    bind(src, dst)?;
+    for path in mounts_beneath(dst)? {
+        remount(&path, MsFlags::MS_BIND | MsFlags::MS_RDONLY)?;
+    }
+
+    Ok(())
+}
+/// Lists the mount points of `path` and all mounts beneath it, parents first.
+fn mounts_beneath(path: &Path) -> Result<Vec<PathBuf>, MountError> {
+    let map_err = |source| MountError::MountInfo {
+        path: path.to_path_buf(),
+        source,
+    };
+    // The mount points are listed with symbolic links resolved.
+    let path = fs::canonicalize(path).map_err(map_err)?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(map_err)?;
+
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mount_point)
+        .filter(|mount_point| mount_point.starts_with(&path))
+        .collect())
+}
+
+/// Decodes a mount point of `/proc/self/mountinfo`, in which whitespace and
+/// backslashes are escaped as octal `\NNN`.
+fn unescape_mount_point(field: &str) -> PathBuf {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let octal = tail
+            .get(..3)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match octal {
+            Some(decoded) if byte == b'\\' => {
+                bytes.push(decoded);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use nix::{
+        sched::CloneFlags,
+        sys::statfs::{self, TMPFS_MAGIC},
+    };
+
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn tmpfs_is_limited_to_the_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            init_namespace().is_ok()
+                && tmpfs(path, Some(1 << 20)).is_ok()
+                && statfs::statfs(path).is_ok_and(|stat| {
+                    stat.filesystem_type() == TMPFS_MAGIC
+                        && stat.blocks() * stat.block_size() as u64 == 1 << 20
+                })
+        });
+    }
+
+    #[test]
+    fn sysfs_shows_the_network_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let only_lo = |entries: fs::ReadDir| {
+            let names: Vec<_> = entries.flatten().map(|entry| entry.file_name()).collect();
+            names == ["lo"]
+        };
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWNET, || {
+            init_namespace().is_ok()
+                && sysfs(path, MsFlags::MS_RDONLY).is_ok()
+                && fs::read_dir(path.join("class").join("net")).is_ok_and(only_lo)
+        });
+    }
+
+    #[test]
+    fn remount_keeps_the_other_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            init_namespace().is_ok()
+                && tmpfs(path, None).is_ok()
+                && remount(path, MsFlags::MS_RDONLY).is_ok()
+                && statvfs::statvfs(path).is_ok_and(|stat| {
+                    stat.flags()
+                        .contains(FsFlags::ST_RDONLY | FsFlags::ST_NOSUID | FsFlags::ST_NODEV)
+                })
+        });
+    }
+
+    #[test]
+    fn bind_ro_is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        fs::create_dir(&src).unwrap();
+        fs::create_dir(&dst).unwrap();
+
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            init_namespace().is_ok()
+                && bind_ro(&src, &dst).is_ok()
+                && fs::write(dst.join("file"), "")
+                    .is_err_and(|e| e.raw_os_error() == Some(nix::libc::EROFS))
+                && fs::write(src.join("file"), "").is_ok()
+                && dst.join("file").exists()
+        });
+    }
+
+    #[test]
+    fn bind_ro_covers_nested_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::create_dir(&dst).unwrap();
+
+        let is_read_only = |path: PathBuf| {
+            fs::write(path, "").is_err_and(|e| e.raw_os_error() == Some(nix::libc::EROFS))
+        };
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            init_namespace().is_ok()
+                && tmpfs(&src.join("nested"), None).is_ok()
+                && bind_ro(&src, &dst).is_ok()
+                && is_read_only(dst.join("file"))
+                && is_read_only(dst.join("nested").join("file"))
+                && fs::write(src.join("nested").join("file"), "").is_ok()
+        });
+    }
+
+    #[test]
+    fn unescape_mount_point_decodes_octal() {
+        assert_eq!(
+            unescape_mount_point(r"/mnt/a\040b\134c"),
+            PathBuf::from(r"/mnt/a b\c")
+        );
+        assert_eq!(
+            unescape_mount_point(r"/mnt/\04"),
+            PathBuf::from(r"/mnt/\04")
+        );
+    }
+
+    #[test]
+    fn pivot_root_replaces_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("marker"), "").unwrap();
+
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNS, || {
+            init_namespace().is_ok()
+                && pivot_root(dir.path(), dir.path()).is_ok()
+                && Path::new("/marker").exists()
+                && std::env::current_dir().is_ok_and(|cwd| cwd == Path::new("/"))
+                && fs::read_dir("/").is_ok_and(|entries| entries.count() == 1)
+        });
+    }
+}
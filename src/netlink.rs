@@ -1,17 +1,17 @@
 //! Implements `netlink(3)` functionality
 //!
-//! All functions here create and close a netlink socket on each call.
-//! This is redundant but ensures security, by avoiding having privileged sockets
-//! lingering around, once the appropriate capabilities have been dropped.
+//! All functions here operate on a `NETLINK_ROUTE` socket created by [`socket`].
+//! The socket is privileged and must be dropped before the appropriate
+//! capabilities are dropped, to avoid having it lingering around.
 //!
 //! The code is largely based upon the internals of the `rtnetlink crate`, thank you!
 
-use std::net::IpAddr;
+use std::{io, net::IpAddr};
 
 use log::debug;
 use netlink_packet_core::{
     ErrorMessage, NetlinkDeserializable, NetlinkHeader, NetlinkMessage, NetlinkPayload,
-    NetlinkSerializable, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST,
+    NetlinkSerializable, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
 };
 use netlink_packet_route::{
     address::{AddressAttribute, AddressMessage},
@@ -20,10 +20,20 @@ use netlink_packet_route::{
     AddressFamily, RouteNetlinkMessage,
 };
 use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use nix::{errno::Errno, libc};
 use thiserror::Error;
 
 const DEFAULT_BUF_SIZE: usize = 4096;
 
+/// The smallest MTU for dual-stack interfaces, as IPv6 requires at least 1280
+pub const MIN_MTU: u32 = 1280;
+
+/// The largest MTU supported by TUN interfaces
+pub const MAX_MTU: u32 = 65535;
+
+/// The buffer size for dumps, matching the largest datagram the kernel sends
+const DUMP_BUF_SIZE: usize = 32768;
+
 #[derive(Error, Debug)]
 pub enum NetlinkError {
     #[error("I/O error: {0}")]
@@ -34,8 +44,19 @@ pub enum NetlinkError {
     Decode(String),
     #[error("{0}")]
     Internal(String),
+    #[error("netlink failed {what}: {errno}")]
+    Kernel { what: String, errno: Errno },
     #[error("interface {name} does not seem to exist")]
     MissingInterface { name: String },
+    #[error("invalid MTU {mtu}, must be between {MIN_MTU} and {MAX_MTU}")]
+    InvalidMtu { mtu: u32 },
+    #[error("cannot remove {addr}/{prefix_len} from {index}, it is not assigned: {errno}")]
+    AddressNotAssigned {
+        index: u32,
+        addr: IpAddr,
+        prefix_len: u8,
+        errno: Errno,
+    },
 }
 
 /// Create a netlink socket and bind it properly
@@ -47,6 +68,14 @@ fn create_socket(protocol: isize) -> Result<Socket, NetlinkError> {
     Ok(socket)
 }
 
+/// Create a `NETLINK_ROUTE` socket for use with the functions in this module
+pub fn socket() -> Result<Socket, NetlinkError> {
+    let socket = create_socket(NETLINK_ROUTE)?;
+    debug!("created netlink socket");
+
+    Ok(socket)
+}
+
 /// Send `msg` over `socket` and ensure that it has been fully sent
 fn send<I: NetlinkSerializable>(
     socket: &mut Socket,
@@ -68,22 +97,101 @@ fn send<I: NetlinkSerializable>(
     Ok(())
 }
 
+/// Check that `resp` acknowledges a request, which is an error message with
+/// code zero, and otherwise describe the failure with `what`
+fn check_ack(
+    resp: NetlinkMessage<RouteNetlinkMessage>,
+    what: impl FnOnce() -> String,
+) -> Result<(), NetlinkError> {
+    match resp.payload {
+        NetlinkPayload::Error(ErrorMessage { code: None, .. }) => Ok(()),
+        NetlinkPayload::Error(ErrorMessage {
+            code: Some(code), ..
+        }) => Err(NetlinkError::Kernel {
+            what: what(),
+            errno: Errno::from_raw(-code.get()),
+        }),
+        payload => Err(NetlinkError::Internal(format!(
+            "netlink sent an unexpected reply {}: {payload:?}",
+            what()
+        ))),
+    }
+}
+
 /// Receive on `socket` and deserialize into `I`
 fn recv<I: NetlinkDeserializable>(socket: &mut Socket) -> Result<NetlinkMessage<I>, NetlinkError> {
     let mut buf = vec![0_u8; DEFAULT_BUF_SIZE];
-    socket.recv(&mut &mut buf[..], 0)?;
+    let n = recv_datagram(socket, &mut buf)?;
 
     // Version mismatch when wrapping
     // `NetlinkError::Decode(netlink_packet_utils::errors::DecodeError)`
-    NetlinkMessage::deserialize(&buf).map_err(|e| NetlinkError::Decode(e.to_string()))
+    NetlinkMessage::deserialize(&buf[..n]).map_err(|e| NetlinkError::Decode(e.to_string()))
 }
 
-/// Return the index of an interface given by its name
-pub fn get_index(name: &str) -> Result<u32, NetlinkError> {
-    let mut socket = create_socket(NETLINK_ROUTE)?;
-    debug!("created netlink socket to find {name}");
+/// Receive the next datagram on `socket` into `buf`, growing it if the
+/// datagram would not fit, and return its length
+///
+/// Netlink silently truncates datagrams exceeding the buffer, which happens
+/// for links with many attributes, so the length is peeked at first.
+fn recv_datagram(socket: &mut Socket, buf: &mut Vec<u8>) -> Result<usize, NetlinkError> {
+    Ok(recv_sized(buf, |buf, flags| {
+        socket.recv(&mut &mut buf[..], flags)
+    })?)
+}
+
+/// Receive a datagram into `buf` with `recv`, which takes the buffer and the
+/// flags of `recv(2)`, growing `buf` if the datagram would not fit
+fn recv_sized(
+    buf: &mut Vec<u8>,
+    mut recv: impl FnMut(&mut [u8], libc::c_int) -> io::Result<usize>,
+) -> io::Result<usize> {
+    // With `MSG_TRUNC`, the full length is returned even if it exceeds `buf`.
+    let len = recv(buf, libc::MSG_PEEK | libc::MSG_TRUNC)?;
+    if len > buf.len() {
+        buf.resize(len, 0);
+    }
 
-    // Construct the netlink message
+    recv(buf, 0)
+}
+
+/// Receive on `socket` until the end of a multipart dump and deserialize all
+/// of its parts into `I`
+fn recv_dump<I: NetlinkDeserializable>(socket: &mut Socket) -> Result<Vec<I>, NetlinkError> {
+    let mut msgs = Vec::new();
+    let mut buf = vec![0_u8; DUMP_BUF_SIZE];
+
+    loop {
+        let n = recv_datagram(socket, &mut buf)?;
+
+        // A single datagram may contain multiple messages.
+        let mut offset = 0;
+        while offset < n {
+            let msg: NetlinkMessage<I> = NetlinkMessage::deserialize(&buf[offset..n])
+                .map_err(|e| NetlinkError::Decode(e.to_string()))?;
+            let len = msg.header.length as usize;
+            if len == 0 {
+                return Err(NetlinkError::Decode("message of length zero".to_string()));
+            }
+            // Messages are aligned to four bytes (`NLMSG_ALIGN`).
+            offset += (len + 3) & !3;
+
+            match msg.payload {
+                NetlinkPayload::Done(_) => return Ok(msgs),
+                NetlinkPayload::InnerMessage(inner) => msgs.push(inner),
+                NetlinkPayload::Error(e) => {
+                    return Err(NetlinkError::Kernel {
+                        what: "dumping".to_string(),
+                        errno: Errno::from_raw(-e.raw_code()),
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Builds the request looking up the interface named `name`.
+fn get_index_request(name: &str) -> NetlinkMessage<RouteNetlinkMessage> {
     let mut link_msg = LinkMessage::default();
     link_msg.attributes.push(LinkAttribute::IfName(name.into()));
     let mut msg = NetlinkMessage::new(
@@ -94,8 +202,14 @@ pub fn get_index(name: &str) -> Result<u32, NetlinkError> {
     msg.header.sequence_number = 1;
     msg.finalize();
 
-    send(&mut socket, &msg)?;
-    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(&mut socket)?;
+    msg
+}
+
+/// Return the index of an interface given by its name
+pub fn get_index(socket: &mut Socket, name: &str) -> Result<u32, NetlinkError> {
+    let msg = get_index_request(name);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
 
     // Parse it down
     let resp = match resp.payload {
@@ -130,11 +244,39 @@ pub fn get_index(name: &str) -> Result<u32, NetlinkError> {
     Ok(resp.header.index)
 }
 
-/// Set an interface up
-pub fn set_up(index: u32) -> Result<(), NetlinkError> {
-    let mut socket = create_socket(NETLINK_ROUTE)?;
-    debug!("created netlink socket to set {index} UP");
+/// Return the indices and names of all interfaces, sorted by index
+pub fn list_interfaces(socket: &mut Socket) -> Result<Vec<(u32, String)>, NetlinkError> {
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::GetLink(LinkMessage::default())),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_DUMP;
+    msg.header.sequence_number = 1;
+    msg.finalize();
+
+    send(socket, &msg)?;
+    let resps: Vec<RouteNetlinkMessage> = recv_dump(socket)?;
+
+    let mut interfaces: Vec<_> = resps
+        .into_iter()
+        .filter_map(|resp| match resp {
+            RouteNetlinkMessage::NewLink(link) => {
+                link.attributes.into_iter().find_map(|attr| match attr {
+                    LinkAttribute::IfName(name) => Some((link.header.index, name)),
+                    _ => None,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    interfaces.sort_unstable();
+    debug!("found {} interfaces", interfaces.len());
 
+    Ok(interfaces)
+}
+
+/// Builds the request setting the interface `index` to UP.
+fn set_up_request(index: u32) -> NetlinkMessage<RouteNetlinkMessage> {
     let mut link_msg = LinkMessage::default();
     link_msg.header.index = index;
     link_msg.header.flags = LinkFlags::Up;
@@ -146,28 +288,80 @@ pub fn set_up(index: u32) -> Result<(), NetlinkError> {
     msg.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE;
     msg.finalize();
 
-    send(&mut socket, &msg)?;
-    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(&mut socket)?;
+    msg
+}
 
-    // Check for errors (ACK is Error with code zero)
-    match resp.payload {
-        NetlinkPayload::Error(ErrorMessage { code: None, .. }) => {}
-        _ => {
-            return Err(NetlinkError::Internal(format!(
-                "netlink failed for unknown reasons while setting {index} UP"
-            )))
-        }
-    }
+/// Set an interface up
+pub fn set_up(socket: &mut Socket, index: u32) -> Result<(), NetlinkError> {
+    let msg = set_up_request(index);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("setting {index} UP"))?;
     debug!("setted interface {index} to UP");
 
     Ok(())
 }
 
-/// Add `addr` to interface `index`
-pub fn add_address(index: u32, addr: IpAddr, prefix_len: u8) -> Result<(), NetlinkError> {
-    let mut socket = create_socket(NETLINK_ROUTE)?;
-    debug!("created socket for adding an IP address to {index}");
+/// Builds the request setting the interface `index` to DOWN.
+fn set_down_request(index: u32) -> NetlinkMessage<RouteNetlinkMessage> {
+    let mut link_msg = LinkMessage::default();
+    link_msg.header.index = index;
+    link_msg.header.flags = LinkFlags::empty();
+    link_msg.header.change_mask = LinkFlags::Up;
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::SetLink(link_msg)),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+    msg.finalize();
+
+    msg
+}
+
+/// Set an interface down
+#[allow(dead_code)]
+pub fn set_down(socket: &mut Socket, index: u32) -> Result<(), NetlinkError> {
+    let msg = set_down_request(index);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("setting {index} DOWN"))?;
+    debug!("setted interface {index} to DOWN");
+
+    Ok(())
+}
 
+/// Set the MTU of an interface
+///
+/// The MTU must be within [`MIN_MTU`] and [`MAX_MTU`], as the interface is
+/// expected to carry both IPv4 and IPv6.
+pub fn set_mtu(socket: &mut Socket, index: u32, mtu: u32) -> Result<(), NetlinkError> {
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        return Err(NetlinkError::InvalidMtu { mtu });
+    }
+
+    let mut link_msg = LinkMessage::default();
+    link_msg.header.index = index;
+    link_msg.attributes.push(LinkAttribute::Mtu(mtu));
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::SetLink(link_msg)),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+    msg.finalize();
+
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("setting the MTU of {index}"))?;
+    debug!("set MTU of interface {index} to {mtu}");
+
+    Ok(())
+}
+
+/// Construct an [`AddressMessage`] for `addr` on interface `index`
+fn address_message(index: u32, addr: IpAddr, prefix_len: u8) -> AddressMessage {
     let mut addr_msg = AddressMessage::default();
 
     addr_msg.header.prefix_len = prefix_len;
@@ -182,6 +376,16 @@ pub fn add_address(index: u32, addr: IpAddr, prefix_len: u8) -> Result<(), Netli
     addr_msg.attributes.push(AddressAttribute::Address(addr));
     addr_msg.attributes.push(AddressAttribute::Local(addr));
 
+    addr_msg
+}
+
+/// Builds the request assigning `addr/prefix_len` to the interface `index`.
+fn add_address_request(
+    index: u32,
+    addr: IpAddr,
+    prefix_len: u8,
+) -> NetlinkMessage<RouteNetlinkMessage> {
+    let addr_msg = address_message(index, addr, prefix_len);
     let mut msg = NetlinkMessage::new(
         NetlinkHeader::default(),
         NetlinkPayload::from(RouteNetlinkMessage::NewAddress(addr_msg)),
@@ -189,30 +393,146 @@ pub fn add_address(index: u32, addr: IpAddr, prefix_len: u8) -> Result<(), Netli
     msg.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE;
     msg.finalize();
 
-    send(&mut socket, &msg)?;
-    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(&mut socket)?;
+    msg
+}
 
-    // Check for errors (ACK is Error with code zero)
-    match resp.payload {
-        NetlinkPayload::Error(ErrorMessage { code: None, .. }) => {}
-        _ => {
-            return Err(NetlinkError::Internal(format!(
-                "netlink failed for unknown reasons adding IP to {index}"
-            )))
+/// Add `addr` to interface `index`
+pub fn add_address(
+    socket: &mut Socket,
+    index: u32,
+    addr: IpAddr,
+    prefix_len: u8,
+) -> Result<(), NetlinkError> {
+    let msg = add_address_request(index, addr, prefix_len);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("adding {addr}/{prefix_len} to {index}"))?;
+    debug!("added IP to {index}");
+
+    Ok(())
+}
+
+/// Builds the request removing `addr/prefix_len` from the interface `index`.
+fn del_address_request(
+    index: u32,
+    addr: IpAddr,
+    prefix_len: u8,
+) -> NetlinkMessage<RouteNetlinkMessage> {
+    let addr_msg = address_message(index, addr, prefix_len);
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::DelAddress(addr_msg)),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+    msg.finalize();
+
+    msg
+}
+
+/// Remove `addr` from interface `index`
+///
+/// Fails with [`NetlinkError::AddressNotAssigned`] if `addr` is not assigned.
+#[allow(dead_code)]
+pub fn del_address(
+    socket: &mut Socket,
+    index: u32,
+    addr: IpAddr,
+    prefix_len: u8,
+) -> Result<(), NetlinkError> {
+    let msg = del_address_request(index, addr, prefix_len);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    match check_ack(resp, || {
+        format!("removing {addr}/{prefix_len} from {index}")
+    }) {
+        Err(NetlinkError::Kernel {
+            errno: errno @ (Errno::ENOENT | Errno::EADDRNOTAVAIL),
+            ..
+        }) => {
+            return Err(NetlinkError::AddressNotAssigned {
+                index,
+                addr,
+                prefix_len,
+                errno,
+            })
         }
+        res => res?,
     }
-    debug!("added IP to {index}");
+    debug!("removed IP from {index}");
 
     Ok(())
 }
 
-/// Sets the interface with `index` as the default gateway for `af`
+/// Return the addresses and their prefix lengths assigned to interface `index`
+pub fn get_addresses(socket: &mut Socket, index: u32) -> Result<Vec<(IpAddr, u8)>, NetlinkError> {
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::GetAddress(AddressMessage::default())),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_DUMP;
+    msg.header.sequence_number = 1;
+    msg.finalize();
+
+    send(socket, &msg)?;
+    let resps: Vec<RouteNetlinkMessage> = recv_dump(socket)?;
+
+    // Like ip(8), prefer the local address over the peer address.
+    let addresses: Vec<_> = resps
+        .into_iter()
+        .filter_map(|resp| match resp {
+            RouteNetlinkMessage::NewAddress(addr_msg) if addr_msg.header.index == index => {
+                let mut local = None;
+                let mut address = None;
+                for attr in addr_msg.attributes {
+                    match attr {
+                        AddressAttribute::Local(addr) => local = Some(addr),
+                        AddressAttribute::Address(addr) => address = Some(addr),
+                        _ => {}
+                    }
+                }
+                local
+                    .or(address)
+                    .map(|addr| (addr, addr_msg.header.prefix_len))
+            }
+            _ => None,
+        })
+        .collect();
+    debug!("found {} IP addresses on {index}", addresses.len());
+
+    Ok(addresses)
+}
+
+/// Check whether `addr` with `prefix_len` is assigned to interface `index`
+pub fn has_address(
+    socket: &mut Socket,
+    index: u32,
+    addr: IpAddr,
+    prefix_len: u8,
+) -> Result<bool, NetlinkError> {
+    Ok(get_addresses(socket, index)?.contains(&(addr, prefix_len)))
+}
+
+/// Add `addr` to interface `index`, unless it is already assigned
 ///
-/// TODO: Consider not exposing `AddressFamily` here
-pub fn set_default_gateway(index: u32, af: AddressFamily) -> Result<(), NetlinkError> {
-    let mut socket = create_socket(NETLINK_ROUTE)?;
-    debug!("created socket for adding default gateway for {:?}", af);
+/// Unlike [`add_address`], this may be called multiple times.
+pub fn ensure_address(
+    socket: &mut Socket,
+    index: u32,
+    addr: IpAddr,
+    prefix_len: u8,
+) -> Result<(), NetlinkError> {
+    if has_address(socket, index, addr, prefix_len)? {
+        debug!("{addr}/{prefix_len} is already assigned to {index}");
+        return Ok(());
+    }
+
+    add_address(socket, index, addr, prefix_len)
+}
 
+/// Construct a static [`RouteMessage`] for `af` through interface `index`
+fn route_message(index: u32, af: AddressFamily) -> RouteMessage {
     let mut route_msg = RouteMessage::default();
     route_msg.header.table = RouteHeader::RT_TABLE_MAIN;
     route_msg.header.protocol = RouteProtocol::Static;
@@ -222,6 +542,13 @@ pub fn set_default_gateway(index: u32, af: AddressFamily) -> Result<(), NetlinkE
 
     route_msg.attributes.push(RouteAttribute::Oif(index));
 
+    route_msg
+}
+
+/// Builds the request adding a default route of family `af` via the
+/// interface `index`.
+fn default_gateway_request(index: u32, af: AddressFamily) -> NetlinkMessage<RouteNetlinkMessage> {
+    let route_msg = route_message(index, af);
     let mut msg = NetlinkMessage::new(
         NetlinkHeader::default(),
         NetlinkPayload::from(RouteNetlinkMessage::NewRoute(route_msg)),
@@ -229,20 +556,521 @@ pub fn set_default_gateway(index: u32, af: AddressFamily) -> Result<(), NetlinkE
     msg.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE;
     msg.finalize();
 
-    send(&mut socket, &msg)?;
-    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(&mut socket)?;
+    msg
+}
 
-    // Check for errors (ACK is Error with code zero)
-    match resp.payload {
-        NetlinkPayload::Error(ErrorMessage { code: None, .. }) => {}
-        e => {
+/// Sets the interface with `index` as the default gateway for `af`
+///
+/// TODO: Consider not exposing `AddressFamily` here
+pub fn set_default_gateway(
+    socket: &mut Socket,
+    index: u32,
+    af: AddressFamily,
+) -> Result<(), NetlinkError> {
+    let msg = default_gateway_request(index, af);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("adding default gateway {af:?}"))?;
+    debug!("added default gateway {:?}", af);
+
+    Ok(())
+}
+
+/// Adds a static route to `dst/prefix_len` of family `af` through the interface
+/// with `index`
+#[allow(dead_code)]
+pub fn add_route(
+    socket: &mut Socket,
+    index: u32,
+    dst: IpAddr,
+    prefix_len: u8,
+    af: AddressFamily,
+) -> Result<(), NetlinkError> {
+    let msg = add_route_request(index, dst, prefix_len, af)?;
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("adding route to {dst}/{prefix_len}"))?;
+    debug!("added route to {dst}/{prefix_len}");
+
+    Ok(())
+}
+
+/// Builds the request adding a route to `dst/prefix_len` of family `af` via the
+/// interface `index`.
+fn add_route_request(
+    index: u32,
+    dst: IpAddr,
+    prefix_len: u8,
+    af: AddressFamily,
+) -> Result<NetlinkMessage<RouteNetlinkMessage>, NetlinkError> {
+    if dst.is_ipv4() != (af == AddressFamily::Inet) {
+        return Err(NetlinkError::Internal(format!(
+            "destination {dst} does not match the address family {af:?}"
+        )));
+    }
+
+    let mut route_msg = route_message(index, af);
+    route_msg.header.destination_prefix_length = prefix_len;
+    route_msg
+        .attributes
+        .push(RouteAttribute::Destination(dst.into()));
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::NewRoute(route_msg)),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE;
+    msg.finalize();
+
+    Ok(msg)
+}
+
+/// Removes the default gateway for `af` through the interface with `index`
+#[allow(dead_code)]
+pub fn del_route(socket: &mut Socket, index: u32, af: AddressFamily) -> Result<(), NetlinkError> {
+    let msg = del_route_request(index, af);
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || format!("removing default gateway {af:?}"))?;
+    debug!("removed default gateway {:?}", af);
+
+    Ok(())
+}
+
+/// Builds the request removing the default route of family `af` via the
+/// interface `index`.
+fn del_route_request(index: u32, af: AddressFamily) -> NetlinkMessage<RouteNetlinkMessage> {
+    let route_msg = route_message(index, af);
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::DelRoute(route_msg)),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+    msg.finalize();
+
+    msg
+}
+
+/// Removes the route to `destination/prefix_len` through the interface with
+/// `index`, optionally only the one via `gateway`
+#[allow(dead_code)]
+pub fn del_specific_route(
+    socket: &mut Socket,
+    index: u32,
+    destination: IpAddr,
+    prefix_len: u8,
+    gateway: Option<IpAddr>,
+) -> Result<(), NetlinkError> {
+    let msg = del_specific_route_request(index, destination, prefix_len, gateway)?;
+    send(socket, &msg)?;
+    let resp: NetlinkMessage<RouteNetlinkMessage> = recv(socket)?;
+
+    check_ack(resp, || {
+        format!("removing route to {destination}/{prefix_len}")
+    })?;
+    debug!("removed route to {destination}/{prefix_len}");
+
+    Ok(())
+}
+
+/// Builds the request removing the route to `destination/prefix_len` via the
+/// interface `index`, optionally only the one via `gateway`.
+fn del_specific_route_request(
+    index: u32,
+    destination: IpAddr,
+    prefix_len: u8,
+    gateway: Option<IpAddr>,
+) -> Result<NetlinkMessage<RouteNetlinkMessage>, NetlinkError> {
+    let af = match destination {
+        IpAddr::V4(_) => AddressFamily::Inet,
+        IpAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let mut route_msg = route_message(index, af);
+    route_msg.header.destination_prefix_length = prefix_len;
+    route_msg
+        .attributes
+        .push(RouteAttribute::Destination(destination.into()));
+    if let Some(gateway) = gateway {
+        if gateway.is_ipv4() != destination.is_ipv4() {
             return Err(NetlinkError::Internal(format!(
-                "netlink failed for unknown reasons default gateway {:?} {:#?}",
-                af, e
-            )))
+                "gateway {gateway} does not match the address family of {destination}"
+            )));
         }
+        route_msg
+            .attributes
+            .push(RouteAttribute::Gateway(gateway.into()));
     }
-    debug!("added default gateway {:?}", af);
 
-    Ok(())
+    let mut msg = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::from(RouteNetlinkMessage::DelRoute(route_msg)),
+    );
+    msg.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+    msg.finalize();
+
+    Ok(msg)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        os::{fd::AsRawFd, unix::net::UnixDatagram},
+    };
+
+    use nix::{libc, sched::CloneFlags};
+
+    use super::*;
+    use crate::testutil;
+
+    /// Returns whether the interface `name` is up, as seen by `ioctl(2)`.
+    fn is_up(name: &str) -> Option<bool> {
+        // SAFETY: An all-zero `ifreq` is valid and the socket is closed below.
+        unsafe {
+            let mut ifr: libc::ifreq = std::mem::zeroed();
+            for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+                *dst = src as libc::c_char;
+            }
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+            let res = libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut ifr);
+            libc::close(fd);
+            (fd >= 0 && res == 0).then(|| i32::from(ifr.ifr_ifru.ifru_flags) & libc::IFF_UP != 0)
+        }
+    }
+
+    #[test]
+    fn set_down_reverts_set_up() {
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            let Ok(mut socket) = socket() else {
+                return false;
+            };
+            let Ok(index) = get_index(&mut socket, "lo") else {
+                return false;
+            };
+            set_up(&mut socket, index).is_ok()
+                && is_up("lo") == Some(true)
+                && set_down(&mut socket, index).is_ok()
+                && is_up("lo") == Some(false)
+                && get_index(&mut socket, "lo").ok() == Some(index)
+        });
+    }
+
+    #[test]
+    fn ensure_address_is_idempotent() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            let Ok(mut socket) = socket() else {
+                return false;
+            };
+            let Ok(index) = get_index(&mut socket, "lo") else {
+                return false;
+            };
+            ensure_address(&mut socket, index, addr, 24).is_ok()
+                && ensure_address(&mut socket, index, addr, 24).is_ok()
+                && has_address(&mut socket, index, addr, 24).is_ok_and(|has| has)
+                && del_address(&mut socket, index, addr, 24).is_ok()
+                && matches!(
+                    del_address(&mut socket, index, addr, 24),
+                    Err(NetlinkError::AddressNotAssigned { .. })
+                )
+        });
+    }
+
+    #[test]
+    fn set_mtu_checks_the_range() {
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            let Ok(mut socket) = socket() else {
+                return false;
+            };
+            let Ok(index) = get_index(&mut socket, "lo") else {
+                return false;
+            };
+            let mut invalid = |mtu| {
+                matches!(
+                    set_mtu(&mut socket, index, mtu),
+                    Err(NetlinkError::InvalidMtu { .. })
+                )
+            };
+            invalid(MIN_MTU - 1)
+                && invalid(MAX_MTU + 1)
+                && set_mtu(&mut socket, index, MIN_MTU).is_ok()
+        });
+    }
+
+    #[test]
+    fn del_route_reverts_set_default_gateway() {
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            let Ok(mut socket) = socket() else {
+                return false;
+            };
+            let Ok(index) = get_index(&mut socket, "lo") else {
+                return false;
+            };
+            set_up(&mut socket, index).is_ok()
+                && set_default_gateway(&mut socket, index, AddressFamily::Inet).is_ok()
+                && set_default_gateway(&mut socket, index, AddressFamily::Inet).is_err()
+                && del_route(&mut socket, index, AddressFamily::Inet).is_ok()
+                && del_route(&mut socket, index, AddressFamily::Inet).is_err()
+        });
+    }
+
+    #[test]
+    fn del_specific_route_matches_the_destination() {
+        let default = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            let Ok(mut socket) = socket() else {
+                return false;
+            };
+            let Ok(index) = get_index(&mut socket, "lo") else {
+                return false;
+            };
+            set_up(&mut socket, index).is_ok()
+                && set_default_gateway(&mut socket, index, AddressFamily::Inet).is_ok()
+                && del_specific_route(&mut socket, index, other, 8, None).is_err()
+                && del_specific_route(&mut socket, index, default, 0, None).is_ok()
+                && del_route(&mut socket, index, AddressFamily::Inet).is_err()
+        });
+    }
+
+    #[test]
+    fn add_route_adds_the_destination() {
+        let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            let Ok(mut socket) = socket() else {
+                return false;
+            };
+            let Ok(index) = get_index(&mut socket, "lo") else {
+                return false;
+            };
+            set_up(&mut socket, index).is_ok()
+                && add_route(&mut socket, index, dst, 8, AddressFamily::Inet6).is_err()
+                && add_route(&mut socket, index, dst, 8, AddressFamily::Inet).is_ok()
+                && matches!(
+                    add_route(&mut socket, index, dst, 8, AddressFamily::Inet),
+                    Err(NetlinkError::Kernel {
+                        errno: Errno::EEXIST,
+                        ..
+                    })
+                )
+                && del_specific_route(&mut socket, index, dst, 8, None).is_ok()
+        });
+    }
+
+    #[test]
+    fn list_interfaces_of_a_fresh_namespace() {
+        testutil::in_namespaces(CloneFlags::CLONE_NEWNET, || {
+            socket()
+                .and_then(|mut socket| list_interfaces(&mut socket))
+                .is_ok_and(|links| links == [(1, "lo".to_string())])
+        });
+    }
+
+    /// Serializes `msg` and parses it back, as the kernel would receive it.
+    fn roundtrip(msg: &NetlinkMessage<RouteNetlinkMessage>) -> NetlinkMessage<RouteNetlinkMessage> {
+        let mut buf = vec![0; msg.header.length as usize];
+        msg.serialize(&mut buf);
+        NetlinkMessage::deserialize(&buf).unwrap()
+    }
+
+    #[test]
+    fn get_index_request_asks_for_the_name() {
+        let msg = roundtrip(&get_index_request("onion0"));
+
+        assert_eq!(msg.header.flags, NLM_F_REQUEST);
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::GetLink(link)) = msg.payload else {
+            panic!("not a GetLink request: {msg:?}");
+        };
+        assert_eq!(link.header.index, 0);
+        assert_eq!(link.attributes, [LinkAttribute::IfName("onion0".into())]);
+    }
+
+    #[test]
+    fn set_up_request_only_changes_the_up_flag() {
+        let msg = roundtrip(&set_up_request(7));
+
+        assert_eq!(
+            msg.header.flags,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE
+        );
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::SetLink(link)) = msg.payload else {
+            panic!("not a SetLink request: {msg:?}");
+        };
+        assert_eq!(link.header.index, 7);
+        assert_eq!(link.header.flags, LinkFlags::Up);
+        assert_eq!(link.header.change_mask, LinkFlags::Up);
+    }
+
+    #[test]
+    fn set_down_request_only_clears_the_up_flag() {
+        let msg = roundtrip(&set_down_request(7));
+
+        assert_eq!(msg.header.flags, NLM_F_REQUEST | NLM_F_ACK);
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::SetLink(link)) = msg.payload else {
+            panic!("not a SetLink request: {msg:?}");
+        };
+        assert_eq!(link.header.index, 7);
+        assert_eq!(link.header.flags, LinkFlags::empty());
+        assert_eq!(link.header.change_mask, LinkFlags::Up);
+    }
+
+    #[test]
+    fn add_address_request_assigns_ipv4() {
+        let addr = IpAddr::V4(Ipv4Addr::new(169, 254, 42, 1));
+        let msg = roundtrip(&add_address_request(3, addr, 24));
+
+        assert_eq!(
+            msg.header.flags,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE
+        );
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewAddress(addr_msg)) = msg.payload
+        else {
+            panic!("not a NewAddress request: {msg:?}");
+        };
+        assert_eq!(addr_msg.header.index, 3);
+        assert_eq!(addr_msg.header.prefix_len, 24);
+        assert_eq!(addr_msg.header.family, AddressFamily::Inet);
+        assert_eq!(
+            addr_msg.attributes,
+            [
+                AddressAttribute::Address(addr),
+                AddressAttribute::Local(addr)
+            ]
+        );
+    }
+
+    #[test]
+    fn add_address_request_assigns_ipv6() {
+        let addr = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let msg = roundtrip(&add_address_request(3, addr, 96));
+
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewAddress(addr_msg)) = msg.payload
+        else {
+            panic!("not a NewAddress request: {msg:?}");
+        };
+        assert_eq!(addr_msg.header.prefix_len, 96);
+        assert_eq!(addr_msg.header.family, AddressFamily::Inet6);
+        assert!(addr_msg.attributes.contains(&AddressAttribute::Local(addr)));
+    }
+
+    #[test]
+    fn default_gateway_request_routes_everything_via_the_interface() {
+        for af in [AddressFamily::Inet, AddressFamily::Inet6] {
+            let msg = roundtrip(&default_gateway_request(5, af));
+
+            assert_eq!(
+                msg.header.flags,
+                NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE
+            );
+            let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(route)) = msg.payload
+            else {
+                panic!("not a NewRoute request: {msg:?}");
+            };
+            assert_eq!(route.header.address_family, af);
+            assert_eq!(route.header.destination_prefix_length, 0);
+            assert_eq!(route.header.table, RouteHeader::RT_TABLE_MAIN);
+            assert_eq!(route.header.kind, RouteType::Unicast);
+            assert_eq!(route.attributes, [RouteAttribute::Oif(5)]);
+        }
+    }
+
+    #[test]
+    fn del_address_request_removes_the_address() {
+        let addr = IpAddr::V4(Ipv4Addr::new(169, 254, 42, 1));
+        let msg = roundtrip(&del_address_request(3, addr, 24));
+
+        assert_eq!(msg.header.flags, NLM_F_REQUEST | NLM_F_ACK);
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelAddress(addr_msg)) = msg.payload
+        else {
+            panic!("not a DelAddress request: {msg:?}");
+        };
+        assert_eq!(addr_msg.header.index, 3);
+        assert_eq!(addr_msg.header.prefix_len, 24);
+        assert!(addr_msg.attributes.contains(&AddressAttribute::Local(addr)));
+    }
+
+    #[test]
+    fn add_route_request_sets_the_destination() {
+        let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        let msg = roundtrip(&add_route_request(5, dst, 8, AddressFamily::Inet).unwrap());
+
+        assert_eq!(
+            msg.header.flags,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE
+        );
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(route)) = msg.payload else {
+            panic!("not a NewRoute request: {msg:?}");
+        };
+        assert_eq!(route.header.destination_prefix_length, 8);
+        assert!(route.attributes.contains(&RouteAttribute::Oif(5)));
+        assert!(route
+            .attributes
+            .contains(&RouteAttribute::Destination(dst.into())));
+    }
+
+    #[test]
+    fn add_route_request_rejects_mismatched_families() {
+        let dst = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(matches!(
+            add_route_request(5, dst, 128, AddressFamily::Inet),
+            Err(NetlinkError::Internal(_))
+        ));
+    }
+
+    #[test]
+    fn del_route_request_removes_the_default_route() {
+        let msg = roundtrip(&del_route_request(5, AddressFamily::Inet6));
+
+        assert_eq!(msg.header.flags, NLM_F_REQUEST | NLM_F_ACK);
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRoute(route)) = msg.payload else {
+            panic!("not a DelRoute request: {msg:?}");
+        };
+        assert_eq!(route.header.address_family, AddressFamily::Inet6);
+        assert_eq!(route.header.destination_prefix_length, 0);
+        assert_eq!(route.attributes, [RouteAttribute::Oif(5)]);
+    }
+
+    #[test]
+    fn del_specific_route_request_matches_the_gateway() {
+        let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        let gateway = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let msg = roundtrip(&del_specific_route_request(5, dst, 8, Some(gateway)).unwrap());
+
+        let NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRoute(route)) = msg.payload else {
+            panic!("not a DelRoute request: {msg:?}");
+        };
+        assert_eq!(route.header.address_family, AddressFamily::Inet);
+        assert!(route
+            .attributes
+            .contains(&RouteAttribute::Gateway(gateway.into())));
+
+        let gateway = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(del_specific_route_request(5, dst, 8, Some(gateway)).is_err());
+    }
+
+    #[test]
+    fn recv_sized_grows_the_buffer() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let datagram: Vec<u8> = (0..3 * DEFAULT_BUF_SIZE).map(|i| i as u8).collect();
+        tx.send(&datagram).unwrap();
+        tx.send(b"next").unwrap();
+
+        let mut buf = vec![0; DEFAULT_BUF_SIZE];
+        let recv = |buf: &mut [u8], flags| {
+            // SAFETY: The pointer and length describe the valid buffer `buf`.
+            let n =
+                unsafe { libc::recv(rx.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), flags) };
+            usize::try_from(n).map_err(|_| io::Error::last_os_error())
+        };
+        let n = recv_sized(&mut buf, recv).unwrap();
+        assert_eq!(n, datagram.len());
+        assert_eq!(buf, datagram);
+
+        // The next datagram is received as a whole, too.
+        let n = recv_sized(&mut buf, recv).unwrap();
+        assert_eq!(&buf[..n], b"next");
+    }
 }
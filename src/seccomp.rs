@@ -0,0 +1,183 @@
+//! Implements `seccomp(2)` filtering of the command
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use nix::libc;
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use thiserror::Error;
+
+/// The name of the filter used from a JSON policy file
+pub const FILTER_NAME: &str = "command";
+
+/// The system calls denied by [`Profile::DenyDangerous`]
+///
+/// These modify the kernel or system-wide state, leave the namespaces, tamper
+/// with other processes or expose rarely needed parts of the kernel, which
+/// ordinary applications do without.
+const DENIED_SYSCALLS: [libc::c_long; 34] = [
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fsopen,
+    libc::SYS_fspick,
+    libc::SYS_init_module,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_move_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_open_tree,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+#[derive(Error, Debug)]
+pub enum SeccompError {
+    #[error("failed to read {path:?}: {source}")]
+    IO { path: PathBuf, source: io::Error },
+    #[error("{path:?} does not contain a filter named {FILTER_NAME:?}")]
+    MissingFilter { path: PathBuf },
+    #[error("failed to compile seccomp filter: {0}")]
+    Compile(#[from] seccompiler::Error),
+}
+
+/// A policy for `seccomp(2)`
+#[derive(Clone, Debug)]
+pub enum Profile {
+    /// Fails the [`DENIED_SYSCALLS`] with `EPERM` and allows all others.
+    ///
+    /// This is a denylist, not a default-deny sandbox.
+    DenyDangerous,
+    /// Uses the filter named [`FILTER_NAME`] from a JSON file in the format of
+    /// `seccompiler`.
+    File(PathBuf),
+}
+
+/// Compiles `profile` into a BPF program for the architecture we run on.
+pub fn compile(profile: &Profile) -> Result<BpfProgram, SeccompError> {
+    let arch = TargetArch::try_from(env::consts::ARCH).map_err(seccompiler::Error::Backend)?;
+    let program = match profile {
+        Profile::DenyDangerous => {
+            let rules: BTreeMap<_, _> = DENIED_SYSCALLS
+                .into_iter()
+                .map(|syscall| (syscall, Vec::new()))
+                .collect();
+            let filter = SeccompFilter::new(
+                rules,
+                SeccompAction::Allow,
+                SeccompAction::Errno(libc::EPERM as u32),
+                arch,
+            )
+            .map_err(seccompiler::Error::Backend)?;
+            filter.try_into().map_err(seccompiler::Error::Backend)?
+        }
+        Profile::File(path) => compile_file(path, arch)?,
+    };
+    debug!("compiled seccomp filter {profile:?}");
+
+    Ok(program)
+}
+
+/// Compiles the filter named [`FILTER_NAME`] from the JSON file at `path`.
+fn compile_file(path: &Path, arch: TargetArch) -> Result<BpfProgram, SeccompError> {
+    let file = File::open(path).map_err(|source| SeccompError::IO {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    seccompiler::compile_from_json(file, arch)?
+        .remove(FILTER_NAME)
+        .ok_or_else(|| SeccompError::MissingFilter {
+            path: path.to_path_buf(),
+        })
+}
+
+/// Applies `program` to all threads of the calling process, which is inherited
+/// by their future threads and children.
+///
+/// The threads are synchronized with `SECCOMP_FILTER_FLAG_TSYNC`, so that none
+/// of them remains unfiltered.
+pub fn apply(program: &BpfProgram) -> io::Result<()> {
+    seccompiler::apply_filter_all_threads(program).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::io::Write;
+
+    use nix::{
+        errno::Errno,
+        sched::{self, CloneFlags},
+        sys::wait::{self, WaitStatus},
+        unistd::{self, ForkResult},
+    };
+
+    use super::*;
+
+    #[test]
+    fn deny_dangerous_fails_with_eperm() {
+        let program = compile(&Profile::DenyDangerous).unwrap();
+        // The filter is applied in a child, as it cannot be removed again.
+        // SAFETY: The child only performs system calls and exits right away.
+        match unsafe { unistd::fork() }.unwrap() {
+            ForkResult::Child => {
+                let denied = apply(&program).is_ok()
+                    && sched::unshare(CloneFlags::CLONE_NEWUTS) == Err(Errno::EPERM)
+                    && unistd::chdir("/").is_ok();
+                // SAFETY: Exiting without running the handlers of the parent.
+                unsafe { libc::_exit(i32::from(!denied)) }
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(
+                    wait::waitpid(child, None).unwrap(),
+                    WaitStatus::Exited(child, 0)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compile_file_requires_the_filter() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            br#"{"other": {"mismatch_action": "allow", "match_action": "trap", "filter": []}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            compile(&Profile::File(file.path().to_path_buf())),
+            Err(SeccompError::MissingFilter { .. })
+        ));
+        assert!(matches!(
+            compile(&Profile::File(PathBuf::from("/nonexistent"))),
+            Err(SeccompError::IO { .. })
+        ));
+    }
+}
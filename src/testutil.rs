@@ -0,0 +1,52 @@
+//! Helpers for tests which need namespaces
+//!
+//! Creating a user namespace requires no privileges, hence these tests run
+//! wherever unprivileged user namespaces are enabled and are skipped otherwise.
+
+use std::fs;
+
+use nix::{
+    libc,
+    sched::{self, CloneFlags},
+    sys::wait::{self, WaitStatus},
+    unistd::{self, ForkResult, Gid, Uid},
+};
+
+/// The exit code of the child if the namespaces cannot be created
+const EXIT_UNSUPPORTED: i32 = 77;
+
+/// Runs `f` in a forked child within a fresh user namespace, in which the
+/// current user is root, and the other namespaces of `flags`.
+///
+/// Fails the test if `f` returns `false`.  The child is forked, as a process
+/// with multiple threads cannot enter a user namespace.
+pub fn in_namespaces(flags: CloneFlags, f: impl FnOnce() -> bool) {
+    let (uid, gid) = (Uid::current(), Gid::current());
+    // SAFETY: The child only performs the test and exits right away.
+    #[allow(clippy::unwrap_used)]
+    let child = match unsafe { unistd::fork() }.unwrap() {
+        ForkResult::Child => {
+            let setup = || -> Option<()> {
+                sched::unshare(CloneFlags::CLONE_NEWUSER | flags).ok()?;
+                fs::write("/proc/self/setgroups", "deny").ok()?;
+                fs::write("/proc/self/uid_map", format!("0 {uid} 1")).ok()?;
+                fs::write("/proc/self/gid_map", format!("0 {gid} 1")).ok()
+            };
+            let code = match setup() {
+                Some(()) => i32::from(!f()),
+                None => EXIT_UNSUPPORTED,
+            };
+            // SAFETY: Exiting without running the handlers of the parent.
+            unsafe { libc::_exit(code) }
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    #[allow(clippy::unwrap_used)]
+    match wait::waitpid(child, None).unwrap() {
+        WaitStatus::Exited(_, EXIT_UNSUPPORTED) => {
+            eprintln!("skipped, cannot create namespaces {flags:?}");
+        }
+        status => assert_eq!(status, WaitStatus::Exited(child, 0)),
+    }
+}
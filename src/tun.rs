@@ -0,0 +1,58 @@
+//! Implements the creation of TUN interfaces
+
+use std::{io, path::Path};
+
+use log::debug;
+use nix::{
+    errno::Errno,
+    unistd::{self, AccessFlags},
+};
+use smoltcp::phy::{Medium, TunTapInterface};
+use thiserror::Error;
+
+/// The character device through which TUN interfaces are created
+pub const CLONE_DEVICE: &str = "/dev/net/tun";
+
+#[derive(Error, Debug)]
+pub enum TunError {
+    #[error("{CLONE_DEVICE} is not available, load the tun kernel module with `modprobe tun`")]
+    ModuleMissing,
+    #[error("permission denied on {CLONE_DEVICE}, make sure it is readable and writable by you")]
+    PermissionDenied,
+    #[error("failed to open tun interface {name}: {source}")]
+    Open { name: String, source: io::Error },
+}
+
+/// Checks whether the TUN kernel module is available and usable.
+pub fn check_module() -> Result<(), TunError> {
+    if !Path::new(CLONE_DEVICE).exists() {
+        return Err(TunError::ModuleMissing);
+    }
+    if unistd::access(CLONE_DEVICE, AccessFlags::R_OK | AccessFlags::W_OK).is_err() {
+        return Err(TunError::PermissionDenied);
+    }
+
+    Ok(())
+}
+
+/// Creates a TUN interface with the name `name`.
+///
+/// Opening [`CLONE_DEVICE`] fails with `ENOENT` if the device node does not
+/// exist and with `ENODEV` if it exists but no driver is loaded for it; both
+/// are reported as [`TunError::ModuleMissing`], whereas `EACCES` is reported
+/// as [`TunError::PermissionDenied`].
+pub fn open(name: &str) -> Result<TunTapInterface, TunError> {
+    let tun = TunTapInterface::new(name, Medium::Ip).map_err(|source| {
+        match source.raw_os_error().map(Errno::from_raw) {
+            Some(Errno::ENOENT | Errno::ENODEV) => TunError::ModuleMissing,
+            Some(Errno::EACCES) => TunError::PermissionDenied,
+            _ => TunError::Open {
+                name: name.to_string(),
+                source,
+            },
+        }
+    })?;
+    debug!("created tun interface {name}");
+
+    Ok(tun)
+}
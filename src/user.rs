@@ -2,35 +2,285 @@
 //!
 //! All functions require a working procfs mount at `/proc`.
 #![allow(clippy::unused_io_amount)]
-use std::{fs::File, io::Write};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    process::Command,
+};
 
 use log::debug;
-use nix::unistd::{Gid, Uid};
+use nix::{
+    libc,
+    unistd::{Gid, Pid, Uid, User},
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum UserError {
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
+    #[error(
+        "not permitted to write {path}, mapping foreign IDs requires CAP_SETUID and CAP_SETGID"
+    )]
+    MapDenied { path: String },
+    #[error("failed to look up user {uid}: {source}")]
+    Lookup { uid: Uid, source: nix::errno::Errno },
+    #[error("failed to run {helper}: {source}")]
+    HelperSpawn {
+        helper: &'static str,
+        source: io::Error,
+    },
+    #[error("{helper} failed with {status}")]
+    HelperFailed {
+        helper: &'static str,
+        status: std::process::ExitStatus,
+    },
 }
 
-/// Performs a 1-by-1 mapping of two [`Uid`]'s.
+/// Writes all `(inner, outer, count)` entries of a mapping to `path`.
+///
+/// The kernel only accepts a single `write(2)` to the map files, hence all
+/// entries are written at once.
+///
+/// The kernel rejects outer IDs that the caller may not map with `EPERM`,
+/// which is reported as [`UserError::MapDenied`].
+fn write_map(path: &str, mappings: &[(u32, u32, u32)]) -> Result<(), UserError> {
+    let map: String = mappings
+        .iter()
+        .map(|(inner, outer, count)| format!("\t{inner}\t{outer}\t{count}\n"))
+        .collect();
+
+    let mut f = File::create(path)?;
+    f.write(map.as_bytes())
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::EPERM) => UserError::MapDenied {
+                path: path.to_string(),
+            },
+            _ => e.into(),
+        })?;
+
+    Ok(())
+}
+
+/// Performs a mapping of [`Uid`] ranges given as `(inner, outer, count)`.
 ///
 /// This function may only be called once per `user_namespaces(7)`.
-pub fn uid_map(inner: Uid, outer: Uid) -> Result<(), UserError> {
-    let mut f = File::create("/proc/self/uid_map")?;
-    f.write(format!("\t{inner}\t{outer}\t1\n").as_bytes())?;
-    debug!("mapped UID {inner} to {outer}");
+pub fn uid_map(mappings: &[(Uid, Uid, u32)]) -> Result<(), UserError> {
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|(inner, outer, count)| (inner.as_raw(), outer.as_raw(), *count))
+        .collect();
+    write_map("/proc/self/uid_map", &mappings)?;
+    debug!("mapped UIDs {mappings:?}");
 
     Ok(())
 }
 
-/// Performs a 1-by-1 mapping of two [`Gid`]'s.
+/// Performs a mapping of [`Gid`] ranges given as `(inner, outer, count)`.
 ///
 /// This function may only be called once per `user_namespaces(7)`.
-pub fn gid_map(inner: Gid, outer: Gid) -> Result<(), UserError> {
-    let mut f = File::create("/proc/self/gid_map")?;
-    f.write(format!("\t{inner}\t{outer}\t1\n").as_bytes())?;
-    debug!("mapped GID {inner} to {outer}");
+pub fn gid_map(mappings: &[(Gid, Gid, u32)]) -> Result<(), UserError> {
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|(inner, outer, count)| (inner.as_raw(), outer.as_raw(), *count))
+        .collect();
+    write_map("/proc/self/gid_map", &mappings)?;
+    debug!("mapped GIDs {mappings:?}");
+
+    Ok(())
+}
+
+/// Performs a mapping of [`Uid`] ranges for the process `pid` from outside of
+/// its `user_namespaces(7)`.
+///
+/// Unlike within the namespace, this allows mapping foreign UIDs given
+/// `CAP_SETUID`.
+pub fn uid_map_of(pid: Pid, mappings: &[(Uid, Uid, u32)]) -> Result<(), UserError> {
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|(inner, outer, count)| (inner.as_raw(), outer.as_raw(), *count))
+        .collect();
+    write_map(&format!("/proc/{pid}/uid_map"), &mappings)?;
+    debug!("mapped UIDs {mappings:?} of {pid}");
+
+    Ok(())
+}
+
+/// Performs a mapping of [`Gid`] ranges for the process `pid` from outside of
+/// its `user_namespaces(7)`.
+///
+/// Unlike within the namespace, this allows mapping foreign GIDs given
+/// `CAP_SETGID`.
+pub fn gid_map_of(pid: Pid, mappings: &[(Gid, Gid, u32)]) -> Result<(), UserError> {
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|(inner, outer, count)| (inner.as_raw(), outer.as_raw(), *count))
+        .collect();
+    write_map(&format!("/proc/{pid}/gid_map"), &mappings)?;
+    debug!("mapped GIDs {mappings:?} of {pid}");
+
+    Ok(())
+}
+
+/// Maps `uid` onto itself.
+///
+/// This function may only be called once per `user_namespaces(7)`.
+pub fn uid_map_identity(uid: Uid) -> Result<(), UserError> {
+    uid_map(&[(uid, uid, 1)])
+}
+
+/// Maps `gid` onto itself.
+///
+/// This function may only be called once per `user_namespaces(7)`.
+pub fn gid_map_identity(gid: Gid) -> Result<(), UserError> {
+    gid_map(&[(gid, gid, 1)])
+}
+
+/// Parses the subordinate ID ranges of `uid` from `path`.
+///
+/// The file consists of `owner:start:count` lines as described in
+/// `subuid(5)`, where `owner` is either the login name or the numeric UID.
+/// Malformed lines are skipped and a missing file yields no ranges.
+fn read_subids(path: &Path, uid: Uid) -> Result<Vec<(u32, u32)>, UserError> {
+    let name = User::from_uid(uid)
+        .map_err(|source| UserError::Lookup { uid, source })?
+        .map(|user| user.name);
+    let uid = uid.to_string();
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let ranges = content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let owner = fields.next()?;
+            let start = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            (owner == uid || Some(owner) == name.as_deref()).then_some((start, count))
+        })
+        .collect();
+
+    Ok(ranges)
+}
+
+/// Returns the subordinate UID ranges of `uid` from `/etc/subuid` as
+/// `(start, count)` pairs.
+pub fn read_subuid(uid: Uid) -> Result<Vec<(u32, u32)>, UserError> {
+    read_subids(Path::new("/etc/subuid"), uid)
+}
+
+/// Returns the subordinate GID ranges of `uid` from `/etc/subgid` as
+/// `(start, count)` pairs.
+///
+/// Like `/etc/subuid`, the file is keyed by user and not by group.
+pub fn read_subgid(uid: Uid) -> Result<Vec<(u32, u32)>, UserError> {
+    read_subids(Path::new("/etc/subgid"), uid)
+}
+
+/// Maps `inner` to `outer` and lays out `ranges` consecutively from inner ID
+/// 0, leaving out `inner`.
+fn subid_mappings(inner: u32, outer: u32, ranges: &[(u32, u32)]) -> Vec<(u32, u32, u32)> {
+    let mut mappings = vec![(inner, outer, 1)];
+    let mut next = 0_u32;
+    for &(mut start, mut count) in ranges {
+        while count > 0 {
+            if next == inner {
+                next += 1;
+                continue;
+            }
+            let len = if next < inner {
+                count.min(inner - next)
+            } else {
+                count
+            };
+            mappings.push((next, start, len));
+            next = next.saturating_add(len);
+            start = start.saturating_add(len);
+            count -= len;
+        }
+    }
+
+    mappings
+}
+
+/// Builds a [`uid_map`] mapping `inner` to `uid` and the subordinate UIDs of
+/// `uid` around it.
+pub fn uid_map_from_subuid(inner: Uid, uid: Uid) -> Result<Vec<(Uid, Uid, u32)>, UserError> {
+    let ranges = read_subuid(uid)?;
+
+    Ok(subid_mappings(inner.as_raw(), uid.as_raw(), &ranges)
+        .into_iter()
+        .map(|(inner, outer, count)| (Uid::from_raw(inner), Uid::from_raw(outer), count))
+        .collect())
+}
+
+/// Builds a [`gid_map`] mapping `inner` to `gid` and the subordinate GIDs of
+/// `uid` around it.
+pub fn gid_map_from_subgid(
+    inner: Gid,
+    uid: Uid,
+    gid: Gid,
+) -> Result<Vec<(Gid, Gid, u32)>, UserError> {
+    let ranges = read_subgid(uid)?;
+
+    Ok(subid_mappings(inner.as_raw(), gid.as_raw(), &ranges)
+        .into_iter()
+        .map(|(inner, outer, count)| (Gid::from_raw(inner), Gid::from_raw(outer), count))
+        .collect())
+}
+
+/// Runs the setuid `helper` to write `mappings` for the process `pid`.
+fn run_helper(
+    helper: &'static str,
+    pid: Pid,
+    mappings: &[(u32, u32, u32)],
+) -> Result<(), UserError> {
+    let status = Command::new(helper)
+        .arg(pid.to_string())
+        .args(
+            mappings
+                .iter()
+                .flat_map(|(inner, outer, count)| [inner, outer, count])
+                .map(u32::to_string),
+        )
+        .status()
+        .map_err(|source| UserError::HelperSpawn { helper, source })?;
+    if !status.success() {
+        return Err(UserError::HelperFailed { helper, status });
+    }
+
+    Ok(())
+}
+
+/// Performs the [`Uid`] `mappings` for the process `pid` with `newuidmap(1)`.
+///
+/// Unlike [`uid_map`], this allows mapping subordinate UIDs, but has to be
+/// called from outside of the `user_namespaces(7)`.
+pub fn newuidmap(pid: Pid, mappings: &[(Uid, Uid, u32)]) -> Result<(), UserError> {
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|(inner, outer, count)| (inner.as_raw(), outer.as_raw(), *count))
+        .collect();
+    run_helper("newuidmap", pid, &mappings)?;
+    debug!("mapped UIDs {mappings:?} of {pid} with newuidmap");
+
+    Ok(())
+}
+
+/// Performs the [`Gid`] `mappings` for the process `pid` with `newgidmap(1)`.
+///
+/// Unlike [`gid_map`], this allows mapping subordinate GIDs, but has to be
+/// called from outside of the `user_namespaces(7)`.
+pub fn newgidmap(pid: Pid, mappings: &[(Gid, Gid, u32)]) -> Result<(), UserError> {
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|(inner, outer, count)| (inner.as_raw(), outer.as_raw(), *count))
+        .collect();
+    run_helper("newgidmap", pid, &mappings)?;
+    debug!("mapped GIDs {mappings:?} of {pid} with newgidmap");
 
     Ok(())
 }
@@ -39,15 +289,110 @@ pub fn gid_map(inner: Gid, outer: Gid) -> Result<(), UserError> {
 ///
 /// This function may only be called once per `user_namespaces(7)`.
 pub fn setgroups(allow: bool) -> Result<(), UserError> {
+    setgroups_at("/proc/self/setgroups", allow)
+}
+
+/// Like [`setgroups`], but for the process `pid` from outside of its
+/// `user_namespaces(7)`.
+pub fn setgroups_of(pid: Pid, allow: bool) -> Result<(), UserError> {
+    setgroups_at(&format!("/proc/{pid}/setgroups"), allow)
+}
+
+fn setgroups_at(path: &str, allow: bool) -> Result<(), UserError> {
     let value = if allow {
         "allow\n".as_bytes()
     } else {
         "deny\n".as_bytes()
     };
 
-    let mut f = File::create("/proc/self/setgroups")?;
+    let mut f = File::create(path)?;
     f.write(value)?;
-    debug!("setgroups {allow}");
+    debug!("setgroups {allow} in {path}");
 
     Ok(())
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use nix::{
+        sched::{self, CloneFlags},
+        sys::{
+            prctl,
+            wait::{self, WaitStatus},
+        },
+        unistd::{self, ForkResult},
+    };
+
+    use super::*;
+
+    #[test]
+    fn read_subids_matches_name_and_uid() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            b"root:100000:65536\n\
+              other:300000:65536\n\
+              0:200000:10\n\
+              malformed\n\
+              root:x:5\n",
+        )
+        .unwrap();
+
+        let ranges = read_subids(file.path(), Uid::from_raw(0)).unwrap();
+        assert_eq!(ranges, [(100000, 65536), (200000, 10)]);
+    }
+
+    #[test]
+    fn read_subids_without_file() {
+        let ranges = read_subids(Path::new("/nonexistent/subuid"), Uid::from_raw(0)).unwrap();
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn subid_mappings_skip_the_inner_id() {
+        let mappings = subid_mappings(1000, 1000, &[(100000, 65536)]);
+        assert_eq!(
+            mappings,
+            [(1000, 1000, 1), (0, 100000, 1000), (1001, 101000, 64536)]
+        );
+    }
+
+    #[test]
+    fn subid_mappings_with_map_root() {
+        let mappings = subid_mappings(0, 1000, &[(100000, 10), (200000, 5)]);
+        assert_eq!(mappings, [(0, 1000, 1), (1, 100000, 10), (11, 200000, 5)]);
+    }
+
+    #[test]
+    fn subid_mappings_without_ranges() {
+        assert_eq!(subid_mappings(1000, 1000, &[]), [(1000, 1000, 1)]);
+    }
+
+    #[test]
+    fn uid_map_of_foreign_ids_is_denied() {
+        // SAFETY: The child only drops privileges, maps and exits.
+        let child = match unsafe { unistd::fork() }.unwrap() {
+            ForkResult::Child => {
+                // Root may map any ID, hence become an unprivileged user first,
+                // who stays able to open its own /proc/self files.
+                let nobody = Uid::from_raw(65534);
+                let unprivileged = !Uid::effective().is_root()
+                    || (unistd::setresuid(nobody, nobody, nobody).is_ok()
+                        && prctl::set_dumpable(true).is_ok());
+                let denied = unprivileged
+                    && (sched::unshare(CloneFlags::CLONE_NEWUSER).is_err()
+                        || matches!(
+                            uid_map(&[(Uid::from_raw(0), Uid::from_raw(0), 1)]),
+                            Err(UserError::MapDenied { .. })
+                        ));
+                // SAFETY: Exiting without running the handlers of the parent.
+                unsafe { libc::_exit(i32::from(!denied)) }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        assert_eq!(
+            wait::waitpid(child, None).unwrap(),
+            WaitStatus::Exited(child, 0)
+        );
+    }
+}